@@ -0,0 +1,364 @@
+//! Futures and streams on top of the callback API, for use with async executors. These are
+//! available with the `async` feature.
+//!
+//! Operations that have a callback variant (reads, writes and commands) complete from the thread
+//! that delivers the device's response. Connecting, discovery and subscribing are synchronous
+//! operations in the underlying API, so their futures run the operation on a small shared pool of
+//! threads and complete when it finishes.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use futures_core::Stream;
+
+use ::Result;
+use api::{Callback, Central, CentralEvent, Characteristic, Peripheral, Service,
+          ValueNotification};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::poll_fn;
+    use std::task::Wake;
+    use std::thread::Thread;
+    use std::time::Duration;
+    use Error;
+
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    // polls the future on the current thread, parking it until woken, until the future is ready
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_callback_future_completed_from_thread() {
+        let (future, callback) = CallbackFuture::new();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            callback(Ok(42));
+            // only the first result counts
+            callback(Err(Error::NotConnected));
+        });
+        assert_eq!(block_on(future).unwrap(), 42);
+    }
+
+    #[test]
+    fn test_callback_future_spawn() {
+        let future: CallbackFuture<u32> = CallbackFuture::spawn(|| Err(Error::NotConnected));
+        match block_on(future) {
+            Err(Error::NotConnected) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_spawn_more_than_workers() {
+        let futures: Vec<CallbackFuture<usize>> = (0..MAX_WORKERS * 3)
+            .map(|i| CallbackFuture::spawn(move || {
+                thread::sleep(Duration::from_millis(5));
+                Ok(i)
+            }))
+            .collect();
+
+        let results: Vec<usize> = futures.into_iter().map(|f| block_on(f).unwrap()).collect();
+        assert_eq!(results, (0..MAX_WORKERS * 3).collect::<Vec<usize>>());
+        assert!(WORKERS.lock().unwrap().threads <= MAX_WORKERS);
+    }
+
+    #[test]
+    fn test_handler_stream() {
+        let (mut stream, handler) = HandlerStream::new();
+        thread::spawn(move || {
+            for i in 0..3 {
+                thread::sleep(Duration::from_millis(5));
+                handler(i);
+            }
+        });
+
+        let items: Vec<u32> = (0..3)
+            .map(|_| block_on(poll_fn(|cx| Pin::new(&mut stream).poll_next(cx))))
+            .map(|item| item.unwrap())
+            .collect();
+        assert_eq!(items, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_handler_after_stream_dropped() {
+        let (stream, handler) = HandlerStream::new();
+        drop(stream);
+        // the value is discarded rather than kept around for a stream that no longer exists
+        handler(1u32);
+    }
+}
+
+// the most threads that run blocking operations for futures at once; further operations wait for
+// one of them to become free
+const MAX_WORKERS: usize = 4;
+
+type Job = Box<FnOnce() + Send>;
+
+struct Workers {
+    jobs: VecDeque<Job>,
+    threads: usize,
+    idle: usize,
+}
+
+static WORKERS: Mutex<Workers> = Mutex::new(Workers {
+    jobs: VecDeque::new(), threads: 0, idle: 0,
+});
+static JOB_QUEUED: Condvar = Condvar::new();
+
+// runs the job on one of the worker threads, starting another if they are all busy and there is
+// room for it
+fn run_blocking(job: Job) {
+    let mut workers = WORKERS.lock().unwrap();
+    workers.jobs.push_back(job);
+    if workers.idle > 0 {
+        JOB_QUEUED.notify_one();
+    } else if workers.threads < MAX_WORKERS {
+        workers.threads += 1;
+        thread::spawn(work);
+    }
+}
+
+// gives up the thread's place in the pool if a job panics
+struct WorkerGuard;
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        if let Ok(mut workers) = WORKERS.lock() {
+            workers.threads -= 1;
+        }
+    }
+}
+
+fn work() {
+    let _guard = WorkerGuard;
+    let mut workers = WORKERS.lock().unwrap();
+    loop {
+        match workers.jobs.pop_front() {
+            Some(job) => {
+                drop(workers);
+                job();
+                workers = WORKERS.lock().unwrap();
+            }
+            None => {
+                workers.idle += 1;
+                workers = JOB_QUEUED.wait(workers).unwrap();
+                workers.idle -= 1;
+            }
+        }
+    }
+}
+
+struct FutureState<T> {
+    result: Option<Result<T>>,
+    waker: Option<Waker>,
+}
+
+/// A future that resolves to the result passed to the callback of an operation.
+pub struct CallbackFuture<T> {
+    state: Arc<Mutex<FutureState<T>>>,
+}
+
+impl<T: Send + 'static> CallbackFuture<T> {
+    /// Creates a future along with the callback that completes it. Only the first result passed
+    /// to the callback is kept.
+    pub fn new() -> (CallbackFuture<T>, Callback<T>) {
+        let state = Arc::new(Mutex::new(FutureState { result: None, waker: None }));
+        let future = CallbackFuture { state: state.clone() };
+
+        let callback = Box::new(move |result: Result<T>| {
+            let mut state = state.lock().unwrap();
+            if state.result.is_none() {
+                state.result = Some(result);
+                if let Some(waker) = state.waker.take() {
+                    waker.wake();
+                }
+            }
+        });
+
+        (future, callback)
+    }
+
+    /// Runs a blocking operation on the shared pool of worker threads, returning a future that
+    /// resolves to its result. At most four operations run at once; the rest wait their turn.
+    pub fn spawn<F>(operation: F) -> CallbackFuture<T>
+        where F: FnOnce() -> Result<T> + Send + 'static {
+        let (future, callback) = CallbackFuture::new();
+        run_blocking(Box::new(move || callback(operation())));
+        future
+    }
+}
+
+impl<T> Future for CallbackFuture<T> {
+    type Output = Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T>> {
+        let mut state = self.state.lock().unwrap();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+struct StreamState<T> {
+    items: VecDeque<T>,
+    waker: Option<Waker>,
+}
+
+/// A stream of the values passed to a handler. Like the handler it is built on, the stream never
+/// ends; values received after it has been dropped are discarded.
+pub struct HandlerStream<T> {
+    state: Arc<Mutex<StreamState<T>>>,
+}
+
+impl<T: Send + 'static> HandlerStream<T> {
+    /// Creates a stream along with the handler function that feeds it.
+    pub fn new() -> (HandlerStream<T>, Box<Fn(T) + Send>) {
+        let state = Arc::new(Mutex::new(StreamState { items: VecDeque::new(), waker: None }));
+        let weak: Weak<Mutex<StreamState<T>>> = Arc::downgrade(&state);
+
+        let handler = Box::new(move |item: T| {
+            if let Some(state) = weak.upgrade() {
+                let mut state = state.lock().unwrap();
+                state.items.push_back(item);
+                if let Some(waker) = state.waker.take() {
+                    waker.wake();
+                }
+            }
+        });
+
+        (HandlerStream { state }, handler)
+    }
+}
+
+impl<T> Stream for HandlerStream<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        let mut state = self.state.lock().unwrap();
+        match state.items.pop_front() {
+            Some(item) => Poll::Ready(Some(item)),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Future-returning versions of the [`Peripheral`](../trait.Peripheral.html) operations. This is
+/// implemented for every `Peripheral`.
+///
+/// Reads, writes and commands are built on the callback API and don't tie up a thread. Connecting,
+/// disconnecting, discovery and subscribing only exist as blocking operations, so their futures
+/// each occupy one of a pool of four threads until the device answers, or the operation times
+/// out. While all four are busy, further futures of this kind don't start until one is free.
+pub trait AsyncPeripheral: Peripheral + 'static {
+    /// Creates a connection to the device. See `Peripheral::connect`.
+    fn connect_future(&self) -> CallbackFuture<()> {
+        let peripheral = self.clone();
+        CallbackFuture::spawn(move || peripheral.connect())
+    }
+
+    /// Terminates the connection to the device. See `Peripheral::disconnect`.
+    fn disconnect_future(&self) -> CallbackFuture<()> {
+        let peripheral = self.clone();
+        CallbackFuture::spawn(move || peripheral.disconnect())
+    }
+
+    /// Discovers all services of the device. See `Peripheral::discover_services`.
+    fn discover_services_future(&self) -> CallbackFuture<Vec<Service>> {
+        let peripheral = self.clone();
+        CallbackFuture::spawn(move || peripheral.discover_services())
+    }
+
+    /// Discovers all characteristics of the device. See `Peripheral::discover_characteristics`.
+    fn discover_characteristics_future(&self) -> CallbackFuture<Vec<Characteristic>> {
+        let peripheral = self.clone();
+        CallbackFuture::spawn(move || peripheral.discover_characteristics())
+    }
+
+    /// Reads the complete value of the characteristic. See `Peripheral::read`.
+    fn read_future(&self, characteristic: &Characteristic) -> CallbackFuture<Vec<u8>> {
+        let (future, callback) = CallbackFuture::new();
+        self.read_long_async(characteristic, Some(callback));
+        future
+    }
+
+    /// Sends a request (write) to the characteristic. See `Peripheral::request`.
+    fn request_future(&self, characteristic: &Characteristic,
+                      data: &[u8]) -> CallbackFuture<Vec<u8>> {
+        let (future, callback) = CallbackFuture::new();
+        self.request_async(characteristic, data, Some(callback));
+        future
+    }
+
+    /// Sends a command (write without response) to the characteristic. See `Peripheral::command`.
+    fn command_future(&self, characteristic: &Characteristic, data: &[u8]) -> CallbackFuture<()> {
+        let (future, callback) = CallbackFuture::new();
+        self.command_async(characteristic, data, Some(callback));
+        future
+    }
+
+    /// Enables notify or indicate for the characteristic. See `Peripheral::subscribe`.
+    fn subscribe_future(&self, characteristic: &Characteristic) -> CallbackFuture<()> {
+        let peripheral = self.clone();
+        let characteristic = characteristic.clone();
+        CallbackFuture::spawn(move || peripheral.subscribe(&characteristic))
+    }
+
+    /// Disables notify or indicate for the characteristic. See `Peripheral::unsubscribe`.
+    fn unsubscribe_future(&self, characteristic: &Characteristic) -> CallbackFuture<()> {
+        let peripheral = self.clone();
+        let characteristic = characteristic.clone();
+        CallbackFuture::spawn(move || peripheral.unsubscribe(&characteristic))
+    }
+
+    /// Returns a stream of the value notifications and indications received from the device. As
+    /// with `Peripheral::on_notification`, this should only be used once connected.
+    fn notifications(&self) -> HandlerStream<ValueNotification> {
+        let (stream, handler) = HandlerStream::new();
+        self.on_notification(handler);
+        stream
+    }
+}
+
+impl<P: Peripheral + 'static> AsyncPeripheral for P {}
+
+/// Stream-based versions of the [`Central`](../trait.Central.html) event API. This is implemented
+/// for every `Central`.
+pub trait AsyncCentral<P: Peripheral>: Central<P> {
+    /// Returns a stream of the events that occur for this central. See `Central::on_event`.
+    fn events(&self) -> HandlerStream<CentralEvent> {
+        let (stream, handler) = HandlerStream::new();
+        self.on_event(handler);
+        stream
+    }
+}
+
+impl<P: Peripheral, C: Central<P>> AsyncCentral<P> for C {}
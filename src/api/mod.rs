@@ -1,12 +1,127 @@
+#[cfg(feature = "async")]
+pub mod futures;
+
 use std::fmt;
 use std::fmt::{Display, Formatter, Debug};
 
 use ::Result;
-use std::collections::BTreeSet;
+use std::collections::{BTreeSet, HashMap};
+use std::time::{Duration, Instant};
 use api::UUID::B16;
 use api::UUID::B128;
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn properties() -> PeripheralProperties {
+        let mut properties = PeripheralProperties {
+            address: BDAddr { address: [1, 2, 3, 4, 5, 6] },
+            local_name: Some("Thermometer 2".to_string()),
+            rssi: Some(-60),
+            services: vec![B16(0x1809)],
+            ..PeripheralProperties::default()
+        };
+        properties.service_data.insert(B16(0xFEAA), vec![0x10]);
+        properties.manufacturer_data.insert(0x004C, vec![1, 2]);
+        properties
+    }
+
+    #[test]
+    fn test_name_filter() {
+        assert!(NameFilter::Prefix("Thermo".to_string()).matches("Thermometer"));
+        assert!(NameFilter::Prefix(String::new()).matches("Thermometer"));
+        assert!(!NameFilter::Prefix("thermo".to_string()).matches("Thermometer"));
+        assert!(!NameFilter::Prefix("Thermometers".to_string()).matches("Thermometer"));
+
+        let filter = NameFilter::Matches(Box::new(|name| name.ends_with(" 2")));
+        assert!(filter.matches("Thermometer 2"));
+        assert!(!filter.matches("Thermometer 3"));
+    }
+
+    #[test]
+    fn test_empty_scan_filter() {
+        assert!(ScanFilter::default().matches(&properties()));
+        assert!(ScanFilter::default().matches(&PeripheralProperties::default()));
+    }
+
+    #[test]
+    fn test_scan_filter_services() {
+        let filter = |services| ScanFilter { services, ..Default::default() };
+        assert!(filter(vec![B16(0x1809)]).matches(&properties()));
+        assert!(filter(vec![B16(0x180D), B16(0x1809)]).matches(&properties()));
+        // a service the device only sends data for counts as advertised
+        assert!(filter(vec![B16(0xFEAA)]).matches(&properties()));
+        assert!(!filter(vec![B16(0x180D)]).matches(&properties()));
+    }
+
+    #[test]
+    fn test_scan_filter_name() {
+        let filter = |name| ScanFilter { name: Some(name), ..Default::default() };
+        assert!(filter(NameFilter::Prefix("Thermo".to_string())).matches(&properties()));
+        assert!(!filter(NameFilter::Prefix("Heart".to_string())).matches(&properties()));
+        assert!(filter(NameFilter::Matches(Box::new(|n| n.contains("meter"))))
+            .matches(&properties()));
+        assert!(!filter(NameFilter::Matches(Box::new(|_| false))).matches(&properties()));
+
+        // a device without a name never matches a name filter
+        let mut unnamed = properties();
+        unnamed.local_name = None;
+        assert!(!filter(NameFilter::Matches(Box::new(|_| true))).matches(&unnamed));
+    }
+
+    #[test]
+    fn test_scan_filter_addresses() {
+        let filter = |addresses| ScanFilter { addresses, ..Default::default() };
+        let other = BDAddr { address: [6, 5, 4, 3, 2, 1] };
+        assert!(filter(vec![other, properties().address]).matches(&properties()));
+        assert!(!filter(vec![other]).matches(&properties()));
+    }
+
+    #[test]
+    fn test_scan_filter_manufacturer_ids() {
+        let filter = |manufacturer_ids| ScanFilter { manufacturer_ids, ..Default::default() };
+        assert!(filter(vec![0x0006, 0x004C]).matches(&properties()));
+        assert!(!filter(vec![0x0006]).matches(&properties()));
+    }
+
+    #[test]
+    fn test_scan_filter_min_rssi() {
+        let filter = |min_rssi| ScanFilter { min_rssi: Some(min_rssi), ..Default::default() };
+        assert!(filter(-60).matches(&properties()));
+        assert!(filter(-80).matches(&properties()));
+        assert!(!filter(-59).matches(&properties()));
+
+        // a device whose signal couldn't be measured doesn't match
+        let mut unmeasured = properties();
+        unmeasured.rssi = None;
+        assert!(!filter(-127).matches(&unmeasured));
+    }
+
+    #[test]
+    fn test_scan_filter_all_criteria() {
+        let matching = || ScanFilter {
+            services: vec![B16(0x1809)],
+            name: Some(NameFilter::Prefix("Thermo".to_string())),
+            addresses: vec![properties().address],
+            manufacturer_ids: vec![0x004C],
+            min_rssi: Some(-70),
+        };
+        assert!(matching().matches(&properties()));
+
+        // every criterion has to match
+        assert!(!ScanFilter { services: vec![B16(0x180D)], ..matching() }.matches(&properties()));
+        assert!(!ScanFilter { name: Some(NameFilter::Prefix("Heart".to_string())), ..matching() }
+            .matches(&properties()));
+        assert!(!ScanFilter { addresses: vec![BDAddr::default()], ..matching() }
+            .matches(&properties()));
+        assert!(!ScanFilter { manufacturer_ids: vec![0x0006], ..matching() }
+            .matches(&properties()));
+        assert!(!ScanFilter { min_rssi: Some(-50), ..matching() }.matches(&properties()));
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum AddressType {
     Random,
     Public,
@@ -61,6 +176,9 @@ pub struct ValueNotification {
     pub handle: u16,
     /// The new value of the handle.
     pub value: Vec<u8>,
+    /// True if the value was sent as an indication, which the device expects to be confirmed,
+    /// rather than as a notification. Confirmations are sent automatically.
+    pub indication: bool,
 }
 
 pub type Callback<T> = Box<Fn(Result<T>) + Send>;
@@ -71,7 +189,7 @@ pub type NotificationHandler = Box<Fn(ValueNotification) + Send>;
 
 /// A Bluetooth UUID. These can either be 2 bytes or 16 bytes long. UUIDs uniquely identify various
 /// objects in the Bluetooth universe.
-#[derive(Ord, PartialOrd, Eq, PartialEq, Copy, Clone)]
+#[derive(Ord, PartialOrd, Eq, PartialEq, Hash, Copy, Clone)]
 pub enum UUID {
     B16(u16),
     B128([u8; 16]),
@@ -120,6 +238,174 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// The link layer features supported by a device.
+    pub struct LEFeatureFlags: u64 {
+        const LE_ENCRYPTION = 0x0001;
+        const CONNECTION_PARAMETERS_REQUEST_PROCEDURE = 0x0002;
+        const EXTENDED_REJECT_INDICATION = 0x0004;
+        const SLAVE_INITIATED_FEATURES_EXCHANGE = 0x0008;
+        const PING = 0x0010;
+        const DATA_PACKET_LENGTH_EXTENSION = 0x0020;
+        const LL_PRIVACY = 0x0040;
+        const EXTENDED_SCANNER_FILTER_POLICIES = 0x0080;
+        const LE_2M_PHY = 0x0100;
+        const STABLE_MODULATION_INDEX_TX = 0x0200;
+        const STABLE_MODULATION_INDEX_RX = 0x0400;
+        const LE_CODED_PHY = 0x0800;
+        const LE_EXTENDED_ADVERTISING = 0x1000;
+        const LE_PERIODIC_ADVERTISING = 0x2000;
+        const CHANNEL_SELECTION_ALGORITHM_2 = 0x4000;
+        const POWER_CLASS_1 = 0x8000;
+        const MINIMUM_NUMBER_OF_USED_CHANNELS_PROCEDURE = 0x10000;
+    }
+}
+
+/// A physical layer used by a Bluetooth LE connection.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Phy {
+    /// The original 1 Mbit/s PHY, which every device supports
+    Le1M,
+    /// The 2 Mbit/s PHY, for higher throughput
+    Le2M,
+    /// The coded PHY, for longer range at a lower data rate
+    LeCoded,
+}
+
+impl Default for Phy {
+    fn default() -> Self { Phy::Le1M }
+}
+
+impl Phy {
+    pub fn from_u8(v: u8) -> Option<Phy> {
+        match v {
+            1 => Some(Phy::Le1M),
+            2 => Some(Phy::Le2M),
+            3 => Some(Phy::LeCoded),
+            _ => None,
+        }
+    }
+
+    pub fn num(&self) -> u8 {
+        match *self {
+            Phy::Le1M => 1,
+            Phy::Le2M => 2,
+            Phy::LeCoded => 3,
+        }
+    }
+}
+
+bitflags! {
+    /// A set of PHYs that may be used for a connection. An empty set leaves the choice to the
+    /// controller.
+    pub struct PhyFlags: u8 {
+        const LE_1M = 0x01;
+        const LE_2M = 0x02;
+        const LE_CODED = 0x04;
+    }
+}
+
+/// The coding to prefer when transmitting on the coded PHY.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CodedPhyOptions {
+    NoPreference,
+    /// Two symbols per bit, for about twice the range of the 1M PHY
+    S2,
+    /// Eight symbols per bit, for about four times the range of the 1M PHY
+    S8,
+}
+
+impl Default for CodedPhyOptions {
+    fn default() -> Self { CodedPhyOptions::NoPreference }
+}
+
+impl CodedPhyOptions {
+    pub fn num(&self) -> u16 {
+        match *self {
+            CodedPhyOptions::NoPreference => 0,
+            CodedPhyOptions::S2 => 1,
+            CodedPhyOptions::S8 => 2,
+        }
+    }
+}
+
+/// The Bluetooth version and implementation of a device, as reported by its link layer.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RemoteVersion {
+    /// The version of the Bluetooth Core Specification, e.g. 9 for 5.0
+    pub version: u8,
+    /// The company ID of the manufacturer of the device's Bluetooth controller
+    pub manufacturer: u16,
+    /// A manufacturer-specific revision number
+    pub subversion: u16,
+}
+
+/// Returns the name of the company with the given Bluetooth SIG company ID, for the manufacturers
+/// of common Bluetooth controllers. Returns `None` for other IDs.
+pub fn company_name(id: u16) -> Option<&'static str> {
+    let name = match id {
+        0 => "Ericsson Technology Licensing",
+        1 => "Nokia Mobile Phones",
+        2 => "Intel Corp.",
+        3 => "IBM Corp.",
+        4 => "Toshiba Corp.",
+        5 => "3Com",
+        6 => "Microsoft",
+        7 => "Lucent",
+        8 => "Motorola",
+        9 => "Infineon Technologies AG",
+        10 => "Qualcomm Technologies International, Ltd. (QTIL)",
+        11 => "Silicon Wave",
+        12 => "Digianswer A/S",
+        13 => "Texas Instruments Inc.",
+        14 => "Parthus Technologies Inc.",
+        15 => "Broadcom Corporation",
+        29 => "Qualcomm",
+        37 => "NXP Semiconductors",
+        48 => "ST Microelectronics",
+        69 => "Atmel Corporation",
+        70 => "MediaTek, Inc.",
+        71 => "Bluegiga",
+        72 => "Marvell Technology Group Ltd.",
+        76 => "Apple, Inc.",
+        89 => "Nordic Semiconductor ASA",
+        93 => "Realtek Semiconductor Corporation",
+        305 => "Cypress Semiconductor",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// The identity and capabilities of a Bluetooth controller.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControllerInfo {
+    /// The public address of the controller
+    pub address: BDAddr,
+    /// The version of the HCI specification the controller implements, e.g. 9 for 5.0
+    pub hci_version: u8,
+    /// A manufacturer-specific revision of the HCI implementation
+    pub hci_revision: u16,
+    /// The version of the link layer the controller implements, e.g. 9 for 5.0
+    pub lmp_version: u8,
+    /// A manufacturer-specific revision of the link layer implementation
+    pub lmp_subversion: u16,
+    /// The company ID of the manufacturer of the controller
+    pub manufacturer: u16,
+    /// The name of the manufacturer, if it is one `company_name` knows about
+    pub manufacturer_name: Option<&'static str>,
+    /// The link layer features the controller supports
+    pub le_features: LEFeatureFlags,
+    /// The combinations of link layer states the controller supports, as a bit field defined by
+    /// the LE Read Supported States command
+    pub le_states: u64,
+    /// The number of devices the controller's white list can hold
+    pub whitelist_size: u8,
+    /// The largest ACL data packet the controller can take, in bytes
+    pub acl_packet_length: u16,
+    /// The number of ACL data packets the controller can buffer
+    pub acl_packets: u16,
+}
+
 /// A Bluetooth characteristic. Characteristics are the main way you will interact with other
 /// bluetooth devices. Characteristics are identified by a UUID which may be standardized
 /// (like 0x2803, which identifies a characteristic for reading heart rate measurements) but more
@@ -153,6 +439,54 @@ impl Display for Characteristic {
     }
 }
 
+/// A characteristic descriptor. Descriptors live in the handle range of a characteristic after its
+/// value, and hold extra information about it, such as a user-readable description (0x2901), the
+/// presentation format of its value (0x2904) or its notification state (0x2902). The standard set
+/// of descriptors can be found [here](https://www.bluetooth.com/specifications/gatt/descriptors).
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Clone)]
+pub struct Descriptor {
+    /// The handle of the descriptor.
+    pub handle: u16,
+    /// The UUID for this descriptor, which identifies its type.
+    pub uuid: UUID,
+}
+
+impl Display for Descriptor {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "handle: 0x{:04X}, uuid: {:?}", self.handle, self.uuid)
+    }
+}
+
+/// A GATT service. Services group together a set of related characteristics, and are identified by
+/// a UUID which may be standardized (like 0x180D for the heart rate service) or vendor-specific.
+/// The standard set of services can be found
+/// [here](https://www.bluetooth.com/specifications/gatt/services).
+///
+/// The same characteristic UUID may appear under several services on a device; the
+/// `characteristics` of a service are only those whose handles fall within its range.
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Clone)]
+pub struct Service {
+    /// The handle of the service declaration.
+    pub start_handle: u16,
+    /// The last handle that belongs to this service.
+    pub end_handle: u16,
+    /// The UUID for this service.
+    pub uuid: UUID,
+    /// True if this is a primary service, false if it is a secondary service (which is only
+    /// meant to be included by other services).
+    pub primary: bool,
+    /// The characteristics that belong to this service. This will be empty until
+    /// `discover_services` is called.
+    pub characteristics: BTreeSet<Characteristic>,
+}
+
+impl Display for Service {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "start handle: 0x{:04X}, end handle: 0x{:04X}, uuid: {:?}, primary: {}",
+               self.start_handle, self.end_handle, self.uuid, self.primary)
+    }
+}
+
 /// The properties of this peripheral, as determined by the advertising reports we've received for
 /// it.
 #[derive(Debug, Default, Clone)]
@@ -165,17 +499,104 @@ pub struct PeripheralProperties {
     pub local_name: Option<String>,
     /// The transmission power level for the device
     pub tx_power_level: Option<i8>,
-    /// Unstructured data set by the device manufacturer
-    pub manufacturer_data: Option<Vec<u8>>,
+    /// The signal strength in dBm of the last advertising report received from the device
+    pub rssi: Option<i8>,
+    /// The services the device advertises
+    pub services: Vec<UUID>,
+    /// The services the device asks centrals to offer, as advertised by the device
+    pub solicited_services: Vec<UUID>,
+    /// Data associated with advertised services, by service UUID
+    pub service_data: HashMap<UUID, Vec<u8>>,
+    /// Unstructured data set by the device manufacturer, by company identifier
+    pub manufacturer_data: HashMap<u16, Vec<u8>>,
     /// Number of times we've seen advertising reports for this device
     pub discovery_count: u32,
     /// True if we've discovered the device before
     pub has_scan_response: bool,
+    /// When we last received an advertising report for this device
+    pub last_seen: Option<Instant>,
+}
+
+/// The parameters used to establish a connection to a device. The scan interval and window are
+/// in units of 0.625 ms, the connection intervals in units of 1.25 ms (from 0x0006 to 0x0C80),
+/// and the supervision timeout in units of 10 ms (from 0x000A to 0x0C80). The supervision timeout
+/// must be longer than `(1 + latency) * max_interval * 2`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ConnectionParameters {
+    /// How often the adapter listens for the device while connecting
+    pub scan_interval: u16,
+    /// How long the adapter listens for the device each scan interval
+    pub scan_window: u16,
+    /// The shortest connection interval to accept
+    pub min_interval: u16,
+    /// The longest connection interval to accept
+    pub max_interval: u16,
+    /// The number of connection events the device may skip
+    pub latency: u16,
+    /// How long the link may go without a packet before it is considered lost
+    pub supervision_timeout: u16,
+}
+
+impl Default for ConnectionParameters {
+    fn default() -> Self {
+        ConnectionParameters {
+            scan_interval: 0x0060,
+            scan_window: 0x0030,
+            min_interval: 0x0018,
+            max_interval: 0x0028,
+            latency: 0x0000,
+            supervision_timeout: 0x002a,
+        }
+    }
+}
+
+/// The largest link layer packets used on a connection, as payload sizes in bytes and
+/// transmission times in microseconds. Without data length extension, packets carry at most 27
+/// bytes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DataLength {
+    pub tx_octets: u16,
+    pub tx_time: u16,
+    pub rx_octets: u16,
+    pub rx_time: u16,
+}
+
+impl Default for DataLength {
+    fn default() -> Self {
+        DataLength { tx_octets: 27, tx_time: 328, rx_octets: 27, rx_time: 328 }
+    }
+}
+
+/// The parameters of an established connection, in the same units as `ConnectionParameters`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// The time between connection events
+    pub interval: u16,
+    /// The number of connection events the device may skip
+    pub latency: u16,
+    /// How long the link may go without a packet before it is considered lost
+    pub supervision_timeout: u16,
+    /// The PHY used to send to the device
+    pub tx_phy: Phy,
+    /// The PHY used to receive from the device
+    pub rx_phy: Phy,
+    /// The largest packets sent and received on the connection
+    pub data_length: DataLength,
 }
 
 /// Peripheral is the device that you would like to communicate with (the "server" of BLE). This
 /// struct contains both the current state of the device (its properties, characteristics, etc.)
 /// as well as functions for communication.
+///
+/// When the device rejects a request, the operation fails with an
+/// [`Error::Att`](../enum.Error.html) describing the error code it sent back.
+///
+/// Synchronous operations fail with `Error::TimedOut` if the device doesn't answer within the
+/// default timeout of the adapter, except for `connect`, which waits 20 seconds. Connecting and
+/// each GATT operation have a `_timeout` variant that takes the timeout to use instead. Link layer
+/// operations carried out by the adapter, such as `read_rssi`, `read_phy` or
+/// `read_remote_features`, are always bounded by the adapter's timeout. Note that a timed out
+/// operation may still be carried out by the device.
 pub trait Peripheral: Send + Sync + Clone + Debug {
     /// Returns the address of the peripheral.
     fn address(&self) -> BDAddr;
@@ -188,24 +609,141 @@ pub trait Peripheral: Send + Sync + Clone + Debug {
     /// `discover_characteristics` or `discover_characteristics_in_range` is called.
     fn characteristics(&self) -> BTreeSet<Characteristic>;
 
+    /// The set of services we've discovered for this device, along with their characteristics.
+    /// This will be empty until `discover_services` is called.
+    fn services(&self) -> BTreeSet<Service>;
+
     /// Returns true iff we are currently connected to the device.
     fn is_connected(&self) -> bool;
 
     /// Creates a connection to the device. This is a synchronous operation; if this method returns
     /// Ok there has been successful connection. Note that peripherals allow only one connection at
     /// a time. Operations that attempt to communicate with a device will fail until it is connected.
+    /// Fails with `Error::TimedOut` if the connection isn't established within 20 seconds.
     fn connect(&self) -> Result<()>;
 
+    /// Creates a connection to the device, waiting at most `timeout` for it to be established.
+    fn connect_timeout(&self, timeout: Duration) -> Result<()>;
+
+    /// Creates a connection to the device using the given connection parameters, rather than
+    /// those chosen by the operating system. This is a synchronous operation, which can be
+    /// aborted by calling `cancel_connect` from another thread. Fails without connecting if the
+    /// parameters are outside the ranges given in `ConnectionParameters`.
+    fn connect_with(&self, params: ConnectionParameters) -> Result<()>;
+
+    /// Aborts a connection attempt that is in progress, which then fails with
+    /// `Error::Cancelled`. Returns an error if there is no connection attempt to abort.
+    fn cancel_connect(&self) -> Result<()>;
+
     /// Terminates a connection to the device. This is a synchronous operation.
     fn disconnect(&self) -> Result<()>;
 
+    /// Negotiates the ATT MTU with the device, offering to receive PDUs of up to `mtu` bytes.
+    /// Returns the agreed MTU, which will be used to size all further requests to the device. This
+    /// is a synchronous operation.
+    fn exchange_mtu(&self, mtu: u16) -> Result<u16>;
+
+    /// Like `exchange_mtu`, but waits at most `timeout` for the device to answer.
+    fn exchange_mtu_timeout(&self, mtu: u16, timeout: Duration) -> Result<u16>;
+
+    /// Reads the signal strength in dBm of the connection to the device from the adapter. This is
+    /// a synchronous operation.
+    fn read_rssi(&self) -> Result<i8>;
+
+    /// Asks the adapter to switch the connection to the device to one of the given PHYs in each
+    /// direction. The device may refuse, and the switch takes effect after a few connection
+    /// events, at which point a `CentralEvent::PhyUpdated` event is emitted. Fails with
+    /// `Error::NotSupported` if the device is known not to support any of the requested PHYs.
+    fn set_phy(&self, tx: PhyFlags, rx: PhyFlags, options: CodedPhyOptions) -> Result<()>;
+
+    /// Asks the adapter to send packets of up to `tx_octets` bytes (from 27 to 251), taking up to
+    /// `tx_time` microseconds (from 328 to 17040), to the device. The lengths in use change once
+    /// the device agrees, at which point a `CentralEvent::DataLengthChanged` event is emitted.
+    /// Fails with `Error::NotSupported` if the device is known not to support data length
+    /// extension.
+    fn set_data_length(&self, tx_octets: u16, tx_time: u16) -> Result<()>;
+
+    /// Reads the PHYs currently used to send to and receive from the device. This is a
+    /// synchronous operation.
+    fn read_phy(&self) -> Result<(Phy, Phy)>;
+
+    /// Returns the link layer features supported by the device, which are read each time it
+    /// connects. Returns `None` if they have not been read yet.
+    fn remote_features(&self) -> Option<LEFeatureFlags>;
+
+    /// Reads the link layer features supported by the device. This is a synchronous operation.
+    fn read_remote_features(&self) -> Result<LEFeatureFlags>;
+
+    /// Returns the version information of the device, which is read each time it connects.
+    /// Returns `None` if it has not been read yet.
+    fn remote_version(&self) -> Option<RemoteVersion>;
+
+    /// Reads the version information of the device. This is a synchronous operation.
+    fn read_remote_version(&self) -> Result<RemoteVersion>;
+
+    /// Returns the parameters of the current connection to the device, or `None` if it is not
+    /// connected.
+    fn connection_parameters(&self) -> Option<ConnectionInfo>;
+
+    /// Asks the adapter to change the parameters of the connection to the device, in the units
+    /// used by `ConnectionParameters`. The update takes effect after a few connection events, at
+    /// which point a `CentralEvent::ConnectionParametersUpdated` event is emitted. That event is
+    /// also emitted when the parameters change at the request of the device, which the operating
+    /// system accepts or rejects on our behalf.
+    fn update_connection_parameters(&self, min_interval: u16, max_interval: u16, latency: u16,
+                                    timeout: u16) -> Result<()>;
+
+    /// Returns the ATT MTU in use for the connection to the device. This is 23 bytes until a
+    /// larger MTU has been agreed through an MTU exchange.
+    fn mtu(&self) -> u16;
+
+    /// Discovers all primary and secondary services for the device, as well as the characteristics
+    /// within each of them. This is a synchronous operation.
+    fn discover_services(&self) -> Result<Vec<Service>>;
+
+    /// Discovers all services for the device, failing if discovery takes longer than `timeout`.
+    fn discover_services_timeout(&self, timeout: Duration) -> Result<Vec<Service>>;
+
     /// Discovers all characteristics for the device. This is a synchronous operation.
     fn discover_characteristics(&self) -> Result<Vec<Characteristic>>;
 
+    /// Discovers all characteristics for the device, failing if discovery takes longer than
+    /// `timeout`.
+    fn discover_characteristics_timeout(&self, timeout: Duration) -> Result<Vec<Characteristic>>;
+
     /// Discovers characteristics within the specified range of handles. This is a synchronous
     /// operation.
     fn discover_characteristics_in_range(&self, start: u16, end: u16) -> Result<Vec<Characteristic>>;
 
+    /// Like `discover_characteristics_in_range`, but fails if discovery takes longer than
+    /// `timeout`.
+    fn discover_characteristics_in_range_timeout(&self, start: u16, end: u16,
+                                                 timeout: Duration) -> Result<Vec<Characteristic>>;
+
+    /// Discovers the descriptors that belong to the specified characteristic, using the handle
+    /// range between its value and its end handle. This is a synchronous operation.
+    fn discover_descriptors(&self, characteristic: &Characteristic) -> Result<Vec<Descriptor>>;
+
+    /// Like `discover_descriptors`, but fails if discovery takes longer than `timeout`.
+    fn discover_descriptors_timeout(&self, characteristic: &Characteristic,
+                                    timeout: Duration) -> Result<Vec<Descriptor>>;
+
+    /// Sends a request (read) for the value of a descriptor. Synchronously returns either an error
+    /// if the request was not accepted or the response from the device.
+    fn read_descriptor(&self, descriptor: &Descriptor) -> Result<Vec<u8>>;
+
+    /// Like `read_descriptor`, but waits at most `timeout` for the complete value.
+    fn read_descriptor_timeout(&self, descriptor: &Descriptor,
+                               timeout: Duration) -> Result<Vec<u8>>;
+
+    /// Sends a request (write) to set the value of a descriptor. Synchronously returns an error if
+    /// the write was not accepted by the device.
+    fn write_descriptor(&self, descriptor: &Descriptor, data: &[u8]) -> Result<()>;
+
+    /// Like `write_descriptor`, but waits at most `timeout` for the device to accept the write.
+    fn write_descriptor_timeout(&self, descriptor: &Descriptor, data: &[u8],
+                                timeout: Duration) -> Result<()>;
+
     /// Sends a command (`write-without-response`) to the characteristic. Takes an optional callback
     /// that will be notified in case of error or when the command has been successfully acked by the
     /// device.
@@ -215,24 +753,62 @@ pub trait Peripheral: Send + Sync + Clone + Debug {
     /// `Result` with an error set if the command was not accepted by the device.
     fn command(&self, characteristic: &Characteristic, data: &[u8]) -> Result<()>;
 
+    /// Like `command`, but waits at most `timeout` for the command to be sent.
+    fn command_timeout(&self, characteristic: &Characteristic, data: &[u8],
+                       timeout: Duration) -> Result<()>;
+
     /// Sends a request (write) to the device. Takes an optional callback with either an error if
-    /// the request was not accepted or the response from the device.
+    /// the request was not accepted or the response from the device. Values that are too long
+    /// for a single write request are sent as a long write, using prepare write and execute write
+    /// requests; a successful long write is reported as a write response.
     fn request_async(&self, characteristic: &Characteristic,
                      data: &[u8], handler: Option<RequestCallback>);
 
     /// Sends a request (write) to the device. Synchronously returns either an error if the request
-    /// was not accepted or the response from the device.
+    /// was not accepted or the response from the device. Values that are too long for a single
+    /// write request are sent as a long write, using prepare write and execute write requests.
     fn request(&self, characteristic: &Characteristic,
                data: &[u8]) -> Result<Vec<u8>>;
 
+    /// Like `request`, but waits at most `timeout` for the response from the device.
+    fn request_timeout(&self, characteristic: &Characteristic, data: &[u8],
+                       timeout: Duration) -> Result<Vec<u8>>;
+
+    /// Sends a reliable write to the device. The value is queued on the device with prepare write
+    /// requests, and each part echoed back by the device is checked against what was sent before
+    /// the write is executed. If any part doesn't match, the queued write is cancelled. Takes an
+    /// optional callback with either an error if the write failed or the response from the
+    /// device.
+    fn request_reliable_async(&self, characteristic: &Characteristic,
+                              data: &[u8], handler: Option<RequestCallback>);
+
+    /// Sends a reliable write to the device (see `request_reliable_async`). Synchronously returns
+    /// either an error if the write failed or the response from the device.
+    fn request_reliable(&self, characteristic: &Characteristic,
+                        data: &[u8]) -> Result<Vec<u8>>;
+
+    /// Like `request_reliable`, but waits at most `timeout` for the write to complete.
+    fn request_reliable_timeout(&self, characteristic: &Characteristic, data: &[u8],
+                                timeout: Duration) -> Result<Vec<u8>>;
+
     /// Sends a request (read) to the device. Takes an optional callback with either an error if
     /// the request was not accepted or the response from the device.
     fn read_async(&self, characteristic: &Characteristic, handler: Option<RequestCallback>);
 
+    /// Sends a request (read) to the device, followed by as many read blob requests as are needed
+    /// to receive values that don't fit in a single response. Takes an optional callback with
+    /// either an error if the request was not accepted or the response from the device, with the
+    /// complete value.
+    fn read_long_async(&self, characteristic: &Characteristic, handler: Option<RequestCallback>);
+
     /// Sends a request (read) to the device. Synchronously returns either an error if the request
-    /// was not accepted or the response from the device.
+    /// was not accepted or the response from the device. Values that are too long to fit in a
+    /// single response are read in full with read blob requests.
     fn read(&self, characteristic: &Characteristic) -> Result<Vec<u8>>;
 
+    /// Like `read`, but waits at most `timeout` for the complete value.
+    fn read_timeout(&self, characteristic: &Characteristic, timeout: Duration) -> Result<Vec<u8>>;
+
     /// Sends a read-by-type request to device for the range of handles covered by the
     /// characteristic and for the specified declaration UUID. See
     /// [here](https://www.bluetooth.com/specifications/gatt/declarations) for valid UUIDs.
@@ -247,17 +823,33 @@ pub trait Peripheral: Send + Sync + Clone + Debug {
     fn read_by_type(&self, characteristic: &Characteristic,
                     uuid: UUID) -> Result<Vec<u8>>;
 
+    /// Like `read_by_type`, but waits at most `timeout` for the response from the device.
+    fn read_by_type_timeout(&self, characteristic: &Characteristic, uuid: UUID,
+                            timeout: Duration) -> Result<Vec<u8>>;
+
     /// Enables either notify or indicate (depending on support) for the specified characteristic.
     /// This is a synchronous call.
     fn subscribe(&self, characteristic: &Characteristic) -> Result<()>;
 
+    /// Like `subscribe`, but fails if the device doesn't respond within `timeout`.
+    fn subscribe_timeout(&self, characteristic: &Characteristic, timeout: Duration) -> Result<()>;
+
     /// Disables either notify or indicate (depending on support) for the specified characteristic.
     /// This is a synchronous call.
     fn unsubscribe(&self, characteristic: &Characteristic) -> Result<()>;
 
-    /// Registers a handler that will be called when value notification messages are received from
-    /// the device. This method should only be used after a connection has been established. Note
-    /// that the handler will be called in a common thread, so it should not block.
+    /// Like `unsubscribe`, but fails if the device doesn't respond within `timeout`.
+    fn unsubscribe_timeout(&self, characteristic: &Characteristic, timeout: Duration) -> Result<()>;
+
+    /// Cancels all requests to the device that have not yet completed. Their callbacks are called
+    /// with `Error::Cancelled`. Pending requests are also failed with `Error::NotConnected` when
+    /// the connection to the device is lost.
+    fn cancel_pending(&self);
+
+    /// Registers a handler that will be called when value notification or indication messages are
+    /// received from the device. This method should only be used after a connection has been
+    /// established. Note that the handler will be called in a common thread, so it should not
+    /// block.
     fn on_notification(&self, handler: NotificationHandler);
 }
 
@@ -268,10 +860,157 @@ pub enum CentralEvent {
     DeviceUpdated(BDAddr),
     DeviceConnected(BDAddr),
     DeviceDisconnected(BDAddr),
+    ConnectionParametersUpdated(BDAddr),
+    PhyUpdated(BDAddr),
+    DataLengthChanged(BDAddr),
 }
 
 pub type EventHandler = Box<Fn(CentralEvent) + Send>;
 
+/// Matches the local name of a device.
+pub enum NameFilter {
+    /// The name starts with the given string.
+    Prefix(String),
+    /// The name satisfies the given function. This can be used to match regular expressions.
+    Matches(Box<Fn(&str) -> bool + Send>),
+}
+
+impl NameFilter {
+    pub fn matches(&self, name: &str) -> bool {
+        match *self {
+            NameFilter::Prefix(ref prefix) => name.starts_with(prefix.as_str()),
+            NameFilter::Matches(ref f) => f(name),
+        }
+    }
+}
+
+/// Selects the devices a central keeps track of while scanning. A device matches if it satisfies
+/// every criterion that is set; criteria that are lists match if any of their entries do.
+#[derive(Default)]
+pub struct ScanFilter {
+    /// Matches devices that advertise any of these services
+    pub services: Vec<UUID>,
+    /// Matches devices whose local name satisfies this filter
+    pub name: Option<NameFilter>,
+    /// Matches devices with any of these addresses
+    pub addresses: Vec<BDAddr>,
+    /// Matches devices that advertise manufacturer data for any of these company identifiers
+    pub manufacturer_ids: Vec<u16>,
+    /// Matches devices whose signal is at least this strong, in dBm
+    pub min_rssi: Option<i8>,
+}
+
+impl ScanFilter {
+    /// Returns true if a device with the given properties matches the filter.
+    pub fn matches(&self, properties: &PeripheralProperties) -> bool {
+        if !self.services.is_empty() && !self.services.iter().any(|u|
+            properties.services.contains(u) || properties.service_data.contains_key(u)) {
+            return false;
+        }
+
+        if let Some(ref filter) = self.name {
+            match properties.local_name {
+                Some(ref name) if filter.matches(name) => {}
+                _ => return false,
+            }
+        }
+
+        if !self.addresses.is_empty() && !self.addresses.contains(&properties.address) {
+            return false;
+        }
+
+        if !self.manufacturer_ids.is_empty() && !self.manufacturer_ids.iter().any(|id|
+            properties.manufacturer_data.contains_key(id)) {
+            return false;
+        }
+
+        match (self.min_rssi, properties.rssi) {
+            (Some(min), Some(rssi)) => rssi >= min,
+            (Some(_), None) => false,
+            (None, _) => true,
+        }
+    }
+}
+
+/// Which advertising packets the adapter reports while scanning.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ScanFilterPolicy {
+    /// Report all advertising packets, except directed ones not addressed to us.
+    AcceptAll,
+    /// Report only packets from devices in the adapter's whitelist.
+    WhitelistOnly,
+    /// Like `AcceptAll`, but also report directed packets addressed to a resolvable private
+    /// address. Requires a Bluetooth 4.2 controller.
+    AcceptAllWithDirected,
+    /// Like `WhitelistOnly`, but also report directed packets addressed to a resolvable private
+    /// address. Requires a Bluetooth 4.2 controller.
+    WhitelistOnlyWithDirected,
+}
+
+impl Default for ScanFilterPolicy {
+    fn default() -> Self { ScanFilterPolicy::AcceptAll }
+}
+
+impl ScanFilterPolicy {
+    pub fn num(&self) -> u8 {
+        match *self {
+            ScanFilterPolicy::AcceptAll => 0,
+            ScanFilterPolicy::WhitelistOnly => 1,
+            ScanFilterPolicy::AcceptAllWithDirected => 2,
+            ScanFilterPolicy::WhitelistOnlyWithDirected => 3,
+        }
+    }
+}
+
+/// The parameters that control how the adapter scans for devices. Every `interval`, the adapter
+/// listens for `window`; both are in units of 0.625 ms, from 0x0004 (2.5 ms) to 0x4000
+/// (10.24 s), and the window may not be longer than the interval.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ScanParameters {
+    /// How often the adapter starts listening, in units of 0.625 ms
+    pub interval: u16,
+    /// How long the adapter listens each interval, in units of 0.625 ms
+    pub window: u16,
+    /// The type of address the adapter uses in scan requests
+    pub own_address_type: AddressType,
+    /// Which advertising packets are reported
+    pub filter_policy: ScanFilterPolicy,
+    /// Whether to send scan requests to devices to get their scan responses
+    pub active: bool,
+    /// Whether the adapter should report only the first packet it gets from each device
+    pub filter_duplicates: bool,
+}
+
+impl Default for ScanParameters {
+    fn default() -> Self {
+        ScanParameters {
+            interval: 0x0010,
+            window: 0x0010,
+            own_address_type: AddressType::Public,
+            filter_policy: ScanFilterPolicy::AcceptAll,
+            active: false,
+            filter_duplicates: false,
+        }
+    }
+}
+
+impl ScanParameters {
+    /// Listens continuously, finding devices as quickly as possible at the cost of power.
+    pub fn low_latency() -> ScanParameters {
+        ScanParameters { interval: 6554, window: 6554, active: true, ..Default::default() }
+    }
+
+    /// Listens for a quarter of the time (1 s out of every 4 s).
+    pub fn balanced() -> ScanParameters {
+        ScanParameters { interval: 6554, window: 1638, active: true, ..Default::default() }
+    }
+
+    /// Listens for a tenth of the time (0.5 s out of every 5 s), for battery-powered devices.
+    pub fn low_power() -> ScanParameters {
+        ScanParameters { interval: 8192, window: 819, active: false, ..Default::default() }
+    }
+}
+
 /// Central is the "client" of BLE. It's able to scan for and establish connections to peripherals.
 pub trait Central<P : Peripheral>: Send + Sync + Clone {
     /// Registers a function that will receive notifications when events occur for this Central
@@ -284,6 +1023,61 @@ pub trait Central<P : Peripheral>: Send + Sync + Clone {
     /// to subscribers of `on_event` and will be available via `peripherals()`.
     fn start_scan(&self) -> Result<()>;
 
+    /// Starts a scan for BLE devices using the given parameters, which are also used by later
+    /// calls to `start_scan`. If a scan is already running, it is restarted with the new
+    /// parameters.
+    fn start_scan_with(&self, params: ScanParameters) -> Result<()>;
+
+    /// Returns the parameters that will be used for the next scan.
+    fn scan_parameters(&self) -> ScanParameters;
+
+    /// Installs a filter that decides which devices are tracked while scanning, or removes it
+    /// when passed `None`. Devices are only discovered (and their events sent to `on_event`) once
+    /// they match the filter, which is checked against everything they have advertised so far,
+    /// combining their advertising packets and scan responses. Devices that have already been
+    /// discovered keep being updated.
+    fn set_scan_filter(&self, filter: Option<ScanFilter>);
+
+    /// Adds a device to the adapter's white list. Scans using the
+    /// [`WhitelistOnly`](enum.ScanFilterPolicy.html) filter policy only report devices in the
+    /// white list, and `connect_whitelist` only connects to them. Note that the adapter won't
+    /// change its white list while it is being used by a scan or a connection attempt.
+    fn whitelist_add(&self, address: BDAddr, address_type: AddressType) -> Result<()>;
+
+    /// Removes a device from the adapter's white list.
+    fn whitelist_remove(&self, address: BDAddr, address_type: AddressType) -> Result<()>;
+
+    /// Removes all devices from the adapter's white list.
+    fn whitelist_clear(&self) -> Result<()>;
+
+    /// Sets the PHYs the adapter prefers for new connections, in each direction. Empty sets leave
+    /// the choice to the controller.
+    fn set_default_phy(&self, tx: PhyFlags, rx: PhyFlags) -> Result<()>;
+
+    /// Reads the packet size in bytes and transmission time in microseconds that the adapter
+    /// suggests for new connections.
+    fn read_suggested_data_length(&self) -> Result<(u16, u16)>;
+
+    /// Reads the largest packets the adapter supports sending and receiving.
+    fn read_maximum_data_length(&self) -> Result<DataLength>;
+
+    /// Returns the number of devices the adapter's white list can hold.
+    fn read_whitelist_size(&self) -> Result<u8>;
+
+    /// Connects to the first device in the white list that the adapter finds advertising, which
+    /// doesn't need to have been discovered by a scan. This is a synchronous operation; it fails
+    /// with `Error::TimedOut` if no device in the white list is found within the default timeout
+    /// of the adapter. As the adapter creates one connection at a time, this fails while another
+    /// connection attempt is in progress, and `Peripheral::connect` fails until this completes.
+    fn connect_whitelist(&self) -> Result<P>;
+
+    /// Enables expiry of devices that are no longer advertising. Once `timeout` has passed since
+    /// the last advertising report from a device that we're not connected to, a `DeviceLost`
+    /// event is sent for it, and if `evict` is set it is removed from `peripherals()`. A lost
+    /// device that is seen again is announced with `DeviceDiscovered`. Passing `None` disables
+    /// expiry, which is the default.
+    fn set_device_expiry(&self, timeout: Option<Duration>, evict: bool);
+
     /// Control whether to use active or passive scan mode to find BLE devices. Active mode scan
     /// notifies advertises about the scan, whereas passive scan only receives data from the
     /// advertiser. Defaults to use active mode.
@@ -1,6 +1,6 @@
 use std::thread;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use libc;
 
@@ -11,8 +11,9 @@ use bluez::util::handle_error;
 
 use std::fmt;
 use std::fmt::{Debug, Formatter};
-use std::sync::mpsc::{channel, Sender, Receiver};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::VecDeque;
+use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError, SendError};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Mutex;
 use bluez::protocol::hci::ACLData;
 use bluez::protocol::att;
@@ -20,72 +21,96 @@ use bluez::protocol::att;
 use self::StreamMessage::*;
 use api::BDAddr;
 use Error;
+use AttError;
 use api::CommandCallback;
 use api::RequestCallback;
-use bluez::adapter::Adapter;
 use bytes::BytesMut;
 use bytes::BufMut;
 use api::NotificationHandler;
 
+// how often the stream thread checks whether it should shut down
+const POLL_INTERVAL_MS: u64 = 500;
+
 enum StreamMessage  {
     Command(Vec<u8>, Option<CommandCallback>),
     Request(Vec<u8>, Option<RequestCallback>),
     Data(Vec<u8>),
+    Cancel(Error),
 }
 
 impl Debug for StreamMessage {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        match self {
-            &Command(ref data, ref _cb) => write!(f, "Command({:?})", data),
-            &Request(ref data, ref cb) => write!(f, "Request({:?}, cb: {})", data, cb.is_some()),
-            &Data(ref data) => write!(f, "Data({:?})", data),
+        match *self {
+            Command(ref data, ref _cb) => write!(f, "Command({:?})", data),
+            Request(ref data, ref cb) => write!(f, "Request({:?}, cb: {})", data, cb.is_some()),
+            Data(ref data) => write!(f, "Data({:?})", data),
+            Cancel(ref err) => write!(f, "Cancel({:?})", err),
+        }
+    }
+}
+
+/// An ATT request that has been sent to the device and is waiting for its response. Only one of
+/// these may be outstanding at a time.
+struct Transaction {
+    request_opcode: u8,
+    handler: Option<RequestCallback>,
+    deadline: Instant,
+}
+
+impl Transaction {
+    fn is_response(&self, pdu: &[u8]) -> bool {
+        match pdu.first() {
+            Some(&ATT_OP_ERROR_RESP) => pdu.get(1) == Some(&self.request_opcode),
+            Some(&opcode) => att::response_opcode(self.request_opcode) == Some(opcode),
+            None => false,
+        }
+    }
+
+    fn finish(self, result: Result<Vec<u8>>) {
+        if let Some(ref f) = self.handler {
+            f(result);
         }
     }
 }
 
 #[derive(Clone)]
 pub struct ACLStream {
-    adapter: Adapter,
     pub address: BDAddr,
     pub handle: u16,
     fd: i32,
+    local_mtu: u16,
+    mtu: Arc<AtomicUsize>,
+    partial: Arc<Mutex<Option<ACLData>>>,
     should_stop: Arc<AtomicBool>,
     sender: Arc<Mutex<Sender<StreamMessage>>>,
     notification_handlers: Arc<Mutex<Vec<NotificationHandler>>>,
+    on_timeout: Arc<Box<Fn() + Send + Sync>>,
 }
 
 impl ACLStream {
-    pub fn new(adapter: Adapter, address: BDAddr, handle: u16, fd: i32) -> ACLStream {
+    /// Creates a stream for the ATT bearer on socket `fd`. `on_timeout` is called if a
+    /// transaction times out, after which the bearer can't be used again.
+    pub fn new(address: BDAddr, handle: u16, fd: i32, local_mtu: u16,
+               on_timeout: Box<Fn() + Send + Sync>) -> ACLStream {
         info!("Creating new ACLStream for {}, {}, {}", address, handle, fd);
         let (tx, rx) = channel();
         let acl_stream = ACLStream {
-            adapter,
             address,
             handle,
             fd,
+            local_mtu,
+            mtu: Arc::new(AtomicUsize::new(ATT_DEFAULT_LE_MTU as usize)),
+            partial: Arc::new(Mutex::new(None)),
             should_stop: Arc::new(AtomicBool::new(false)),
             sender: Arc::new(Mutex::new(tx)),
             notification_handlers: Arc::new(Mutex::new(vec![])),
+            on_timeout: Arc::new(on_timeout),
         };
 
         {
-            let should_stop = acl_stream.should_stop.clone();
             let stream = acl_stream.clone();
             thread::spawn(move || {
-                let mut msg = rx.recv().unwrap();
-                while !should_stop.load(Ordering::Relaxed) {
-                    match stream.handle_iteration(&mut msg, &rx) {
-                        Ok(_) => msg = rx.recv().unwrap(),
-                        Err(Error::NotConnected) => {
-                            // retry message
-                            thread::sleep(Duration::from_millis(50));
-                            continue;
-                        }
-                        Err(e) => {
-                            error!("Unhandled error {}", e);
-                        }
-                    }
-                }
+                stream.run(rx);
 
                 if let Err(err) = handle_error(unsafe { libc::close(fd) }) {
                     warn!("Failed to close socket {}: {}", fd, err);
@@ -96,66 +121,139 @@ impl ACLStream {
         acl_stream
     }
 
-    fn write_socket(&self, value: &mut [u8], command: bool,
-                    receiver: &Receiver<StreamMessage>) -> Result<Vec<u8>> {
-        debug!("writing {:?}", value);
-        handle_error(unsafe {
-            libc::write(self.fd, value.as_mut_ptr() as *mut libc::c_void, value.len()) as i32
-        })?;
+    fn run(&self, receiver: Receiver<StreamMessage>) {
+        let timeout = Duration::from_secs(ATT_TRANSACTION_TIMEOUT_SECS);
+        let poll_interval = Duration::from_millis(POLL_INTERVAL_MS);
 
-        let mut skipped = vec![];
-        loop {
-            let message = receiver.recv().unwrap();
-            debug!("waiting for confirmation... {:?}", message);
-            if let Data(rec) = message {
-                if rec != value {
-                    skipped.into_iter().for_each(|m|
-                        self.send(m));
-                    return Ok(rec);
-                } else if command {
-                    return Ok(vec![]);
+        let mut queue: VecDeque<(Vec<u8>, Option<RequestCallback>)> = VecDeque::new();
+        let mut outstanding: Option<Transaction> = None;
+
+        while !self.should_stop.load(Ordering::Relaxed) {
+            let wait = match outstanding {
+                Some(ref t) => {
+                    let now = Instant::now();
+                    if t.deadline > now { poll_interval.min(t.deadline - now) } else { Duration::from_secs(0) }
+                }
+                None => poll_interval,
+            };
+
+            match receiver.recv_timeout(wait) {
+                Ok(Command(mut value, handler)) => {
+                    // commands don't get a response, so they don't need to wait their turn
+                    debug!("sending command {:?} to {}", value, self.fd);
+                    let result = self.write_socket(&mut value);
+                    if let Some(ref f) = handler {
+                        f(result);
+                    }
+                }
+                Ok(Request(value, handler)) => {
+                    queue.push_back((value, handler));
+                }
+                Ok(Data(value)) => {
+                    if outstanding.as_ref().map(|t| t.is_response(&value)).unwrap_or(false) {
+                        outstanding.take().unwrap().finish(att::check_response(value));
+                    } else {
+                        warn!("Got unexpected response {:?} from {}", value, self.address);
+                    }
+                }
+                Ok(Cancel(err)) => {
+                    // the outstanding request stays in place so that its response, if it arrives,
+                    // is not mistaken for that of the next request
+                    if let Some(f) = outstanding.as_mut().and_then(|t| t.handler.take()) {
+                        f(Err(err.clone()));
+                    }
+                    queue.drain(..).for_each(|(_, handler)| {
+                        handler.iter().for_each(|f| f(Err(err.clone())));
+                    });
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if outstanding.as_ref().map(|t| t.deadline <= Instant::now()).unwrap_or(false) {
+                        // once a transaction has timed out, no more PDUs may be sent on the bearer
+                        error!("ATT transaction with {} timed out, closing bearer", self.address);
+                        outstanding.take().unwrap().finish(Err(Error::TimedOut(timeout)));
+                        self.should_stop.store(true, Ordering::Relaxed);
+                        (self.on_timeout)();
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    break;
                 }
-            } else {
-                skipped.push(message);
             }
-        }
-    }
 
-    fn handle_iteration(&self, msg: &mut StreamMessage,
-                        receiver: &Receiver<StreamMessage>) -> Result<()> {
-        match *msg {
-            Command(ref mut value, ref handler) => {
-                debug!("sending command {:?} to {}", value, self.fd);
+            // start the next transaction once the previous one is complete
+            while outstanding.is_none() && !self.should_stop.load(Ordering::Relaxed) {
+                let (mut value, handler) = match queue.pop_front() {
+                    Some(request) => request,
+                    None => break,
+                };
 
-                let result = self.write_socket(value, true, receiver)
-                    .map(|_v| ());
-                if let &Some(ref f) = handler {
-                    f(result);
-                }
-            },
-            Request(ref mut value, ref handler) => {
                 debug!("sending request {:?} to {}", value, self.fd);
+                match self.write_socket(&mut value) {
+                    Ok(()) => {
+                        outstanding = Some(Transaction {
+                            request_opcode: value[0],
+                            handler,
+                            deadline: Instant::now() + timeout,
+                        });
+                    }
+                    Err(err) => {
+                        if let Some(ref f) = handler {
+                            f(Err(err));
+                        }
+                    }
+                }
+            }
+        }
+
+        // fail anything that won't be sent now that the bearer is closed
+        outstanding.into_iter().for_each(|t| t.finish(Err(Error::NotConnected)));
+        queue.into_iter().for_each(|(_, handler)| {
+            handler.iter().for_each(|f| f(Err(Error::NotConnected)));
+        });
+        receiver.try_iter().for_each(ACLStream::fail);
+    }
 
-                let result = self.write_socket(value, false, receiver);
-                if let &Some(ref f) = handler {
-                    f(result);
+    fn write_socket(&self, value: &mut [u8]) -> Result<()> {
+        debug!("writing {:?}", value);
+        let mut attempts = 0;
+        loop {
+            let result = handle_error(unsafe {
+                libc::write(self.fd, value.as_mut_ptr() as *mut libc::c_void, value.len()) as i32
+            });
+
+            match result {
+                Ok(_) => return Ok(()),
+                Err(Error::NotConnected) if attempts < 20 => {
+                    // the socket may not be ready yet right after connecting
+                    attempts += 1;
+                    thread::sleep(Duration::from_millis(50));
                 }
-            },
-            Data(ref value) => {
-                debug!("Received data {:?}", value);
+                Err(err) => return Err(err),
             }
         }
+    }
 
-        Ok(())
+    fn fail(message: StreamMessage) {
+        match message {
+            Command(_, Some(f)) => f(Err(Error::NotConnected)),
+            Request(_, Some(f)) => f(Err(Error::NotConnected)),
+            _ => {}
+        }
     }
 
     fn send(&self, message: StreamMessage) {
-        let l = self.sender.lock().unwrap();
-        l.send(message).unwrap();
+        let result = {
+            let l = self.sender.lock().unwrap();
+            l.send(message)
+        };
+
+        if let Err(SendError(message)) = result {
+            // the stream has already shut down
+            ACLStream::fail(message);
+        }
     }
 
     pub fn write(&self, data: &mut [u8], handler: Option<RequestCallback>) {
-        // let mut packet = Protocol::acl(self.handle, ATT_CID, data);
         self.send(Request(data.to_owned(), handler));
     }
 
@@ -163,6 +261,29 @@ impl ACLStream {
         self.send(Command(data.to_owned(), on_done));
     }
 
+    /// Fails all requests that are waiting to be sent or for their response with `err`.
+    pub fn cancel(&self, err: Error) {
+        self.send(Cancel(err));
+    }
+
+    /// Sends an exchange MTU request offering to receive PDUs of up to `mtu` bytes (limited by
+    /// what our socket can receive). The handler gets the raw response from the device.
+    pub fn exchange_mtu(&self, mtu: u16, handler: Option<RequestCallback>) {
+        self.send(Request(att::exchange_mtu_req(mtu.min(self.local_mtu)), handler));
+    }
+
+    /// Returns the ATT MTU for this connection.
+    pub fn mtu(&self) -> u16 {
+        self.mtu.load(Ordering::Relaxed) as u16
+    }
+
+    /// Records the ATT MTU agreed with the device, given the largest PDU the device can receive.
+    pub fn set_mtu(&self, remote_mtu: u16) {
+        let mtu = remote_mtu.min(self.local_mtu).max(ATT_DEFAULT_LE_MTU);
+        info!("using ATT MTU {} for {}", mtu, self.address);
+        self.mtu.store(mtu as usize, Ordering::Relaxed);
+    }
+
     pub fn on_notification(&self, handler: NotificationHandler) {
         let mut list = self.notification_handlers.lock().unwrap();
         list.push(handler);
@@ -170,45 +291,92 @@ impl ACLStream {
 
     pub fn receive(&self, message: &ACLData) {
         debug!("receive message: {:?}", message);
-        // message.data
-        // TODO: handle partial packets
-        if message.cid == ATT_CID {
-            let value = message.data.to_vec();
-            if !value.is_empty() {
-                match value[0] {
-                    ATT_OP_EXCHANGE_MTU_REQ => {
-                        let request = att::mtu_request(&value).unwrap().1;
-                        // is the client MTU smaller than ours?
-                        if request.client_rx_mtu <= self.adapter.info.acl_mtu {
-                            debug!("sending MTU: {}", self.adapter.info.acl_mtu);
-                            // it is, send confirmation
-                            let mut buf = BytesMut::with_capacity(3);
-                            buf.put_u8(ATT_OP_EXCHANGE_MTU_RESP);
-                            buf.put_u16_le(self.adapter.info.acl_mtu);
-                            self.write_cmd(&mut buf, None);
-                        } else {
-                            // TODO: reduce our MTU to client's
-                            error!("client's MTU is larger than ours");
-                            self.write_cmd(&mut [0x01, 0x02, 0x00, 0x00, 0x06], None);
-                        }
+        if message.data.len() < message.len as usize {
+            // this PDU is split across multiple ACL packets; wait for the rest of it
+            *self.partial.lock().unwrap() = Some(message.clone());
+            return;
+        }
+
+        self.receive_pdu(message);
+    }
+
+    pub fn receive_continuation(&self, data: &[u8]) {
+        let message = {
+            let mut partial = self.partial.lock().unwrap();
+            let complete = match *partial {
+                Some(ref mut message) => {
+                    message.data.extend_from_slice(data);
+                    message.data.len() >= message.len as usize
+                }
+                None => {
+                    warn!("got continuation without a start packet: {:?}", data);
+                    return;
+                }
+            };
+
+            if !complete {
+                return;
+            }
+            partial.take().unwrap()
+        };
+
+        self.receive_pdu(&message);
+    }
+
+    fn receive_pdu(&self, message: &ACLData) {
+        if message.cid != ATT_CID || message.data.is_empty() {
+            return;
+        }
+
+        let value = message.data.to_vec();
+        match value[0] {
+            ATT_OP_EXCHANGE_MTU_REQ => {
+                match att::mtu_request(&value) {
+                    Ok((_, request)) => {
+                        debug!("sending MTU: {}", self.local_mtu);
+                        let mut buf = BytesMut::with_capacity(3);
+                        buf.put_u8(ATT_OP_EXCHANGE_MTU_RESP);
+                        buf.put_u16_le(self.local_mtu);
+                        self.write_cmd(&mut buf, None);
+                        self.set_mtu(request.client_rx_mtu);
+                    }
+                    Err(err) => {
+                        error!("failed to parse MTU request: {:?}", err);
                     }
-                    ATT_OP_VALUE_NOTIFICATION => {
-                        debug!("value notification: {:?}", value);
-                        match att::value_notification(&value) {
-                            Ok(notification) => {
-                                let handlers = self.notification_handlers.lock().unwrap();
-                                handlers.iter().for_each(|h| h(notification.1.clone()));
-                            }
-                            Err(err) => {
-                                error!("failed to parse notification: {:?}", err);
-                            }
+                }
+            }
+            ATT_OP_FIND_INFO_REQ | ATT_OP_FIND_BY_TYPE_REQ | ATT_OP_READ_BY_TYPE_REQ |
+            ATT_OP_READ_REQ | ATT_OP_READ_BLOB_REQ | ATT_OP_READ_MULTI_REQ |
+            ATT_OP_READ_BY_GROUP_REQ | ATT_OP_WRITE_REQ | ATT_OP_PREP_WRITE_REQ |
+            ATT_OP_EXEC_WRITE_REQ => {
+                // we don't act as a GATT server, but the device is still owed a response
+                debug!("rejecting request {:?} from {}", value, self.address);
+                let mut buf = att::error_resp(value[0], 0x0000, AttError::RequestNotSupported);
+                self.write_cmd(&mut buf, None);
+            }
+            ATT_OP_VALUE_NOTIFICATION | ATT_OP_VALUE_INDICATION => {
+                debug!("value notification: {:?}", value);
+                match att::value_notification(&value) {
+                    Ok(notification) => {
+                        {
+                            let handlers = self.notification_handlers.lock().unwrap();
+                            handlers.iter().for_each(|h| h(notification.1.clone()));
+                        }
+
+                        if notification.1.indication {
+                            // the device won't send any more indications until we confirm
+                            // this one
+                            self.write_cmd(&mut [ATT_OP_VALUE_CONFIRMATION], None);
                         }
                     }
-                    _ => {
-                        self.send(Data(value));
+                    Err(err) => {
+                        error!("failed to parse notification: {:?}", err);
                     }
                 }
             }
+            _ => {
+                self.send(Data(value));
+            }
         }
     }
 }
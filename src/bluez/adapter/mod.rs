@@ -7,21 +7,182 @@ use std::ffi::CStr;
 use nom;
 use bytes::{BytesMut, BufMut};
 
-use std::collections::{HashSet, HashMap};
+use std::collections::{HashSet, HashMap, VecDeque};
 use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
+use std::time::{Duration, Instant};
 
-use ::Result;
-use api::{CentralEvent, BDAddr, Central};
+use ::{Error, Result};
+use api::{CentralEvent, BDAddr, Central, ScanParameters, ScanFilter, AddressType,
+          ConnectionParameters, PhyFlags, CodedPhyOptions, DataLength, Callback, CommandCallback,
+          ControllerInfo, company_name, Peripheral as ApiPeripheral};
+use num::FromPrimitive;
 
 use bluez::util::handle_error;
 use bluez::protocol::hci;
-use bluez::adapter::peripheral::Peripheral;
+use bluez::protocol::l2cap;
+use bluez::adapter::peripheral::{AdvertisingState, Peripheral};
 use bluez::constants::*;
 use bluez::ioctl;
 use api::EventHandler;
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::Receiver;
+
+    // an adapter without a controller, which only sees the messages it's handed
+    fn detached_adapter() -> ConnectedAdapter {
+        let adapter = Adapter {
+            name: String::from("hci0"),
+            dev_id: 0,
+            addr: BDAddr::default(),
+            typ: AdapterType::BrEdr,
+            states: HashSet::new(),
+            info: HCIDevInfo::default(),
+        };
+        ConnectedAdapter::with_fd(&adapter, -1)
+    }
+
+    fn parameter_updates(adapter: &ConnectedAdapter) -> Arc<Mutex<Vec<BDAddr>>> {
+        let updates = Arc::new(Mutex::new(vec![]));
+        let events = updates.clone();
+        adapter.on_event(Box::new(move |event| {
+            if let CentralEvent::ConnectionParametersUpdated(address) = event {
+                events.lock().unwrap().push(address);
+            }
+        }));
+        updates
+    }
+
+    fn connect(adapter: &ConnectedAdapter, address: BDAddr, handle: u16) {
+        adapter.handle(hci::Message::LEConnComplete(hci::LEConnInfo {
+            status: hci::HCIStatus::Success,
+            handle,
+            role: 0,
+            bdaddr: address,
+            bdaddr_type: 0,
+            interval: 0x18,
+            latency: 0,
+            supervision_timeout: 0x2a,
+            master_clock_accuracy: 0,
+        }));
+    }
+
+    fn conn_update(status: hci::HCIStatus, handle: u16) -> hci::Message {
+        hci::Message::LEConnUpdate(hci::LEConnUpdateInfo {
+            status,
+            handle,
+            interval: 0x28,
+            latency: 0,
+            supervision_timeout: 0x64,
+        })
+    }
+
+    #[test]
+    fn test_device_requested_connection_update() {
+        let adapter = detached_adapter();
+        let updates = parameter_updates(&adapter);
+        let address = BDAddr { address: [1, 2, 3, 4, 5, 6] };
+        connect(&adapter, address, 64);
+
+        // the kernel answers the request, so nothing changes until the controller reports it
+        let request = vec![0x12, 7, 8, 0, 0x18, 0, 0x28, 0, 0, 0, 0x64, 0];
+        adapter.handle(hci::Message::ACLDataPacket(hci::ACLData {
+            handle: 64,
+            cid: LE_SIGNALING_CID,
+            len: request.len() as u16,
+            data: request,
+        }));
+        assert!(updates.lock().unwrap().is_empty());
+
+        adapter.handle(conn_update(hci::HCIStatus::Success, 64));
+        assert_eq!(*updates.lock().unwrap(), vec![address]);
+        let info = adapter.peripheral(address).unwrap().connection_parameters().unwrap();
+        assert_eq!((info.interval, info.latency, info.supervision_timeout), (0x28, 0, 0x64));
+    }
+
+    #[test]
+    fn test_rejected_connection_update() {
+        let adapter = detached_adapter();
+        let updates = parameter_updates(&adapter);
+        let address = BDAddr { address: [1, 2, 3, 4, 5, 6] };
+        connect(&adapter, address, 64);
+
+        adapter.handle(conn_update(hci::HCIStatus::UnacceptableConnectionParameters, 64));
+        assert!(updates.lock().unwrap().is_empty());
+        let info = adapter.peripheral(address).unwrap().connection_parameters().unwrap();
+        assert_eq!(info.interval, 0x18);
+    }
+
+    // queues a command as if it had been written to the controller
+    fn sent_command(adapter: &ConnectedAdapter, opcode: u16)
+                    -> Receiver<Result<Option<Vec<u8>>>> {
+        let (tx, rx) = channel();
+        let mut queue = adapter.commands.lock().unwrap();
+        let sequence = queue.next_sequence;
+        queue.next_sequence += 1;
+        queue.credits -= 1;
+        queue.sent.push(QueuedCommand {
+            id: sequence,
+            opcode,
+            sequence,
+            packet: BytesMut::new(),
+            callback: Some(Box::new(move |result| {
+                let _ = tx.send(result);
+            })),
+        });
+        rx
+    }
+
+    fn command_complete(opcode: u16) -> hci::Message {
+        hci::Message::HCICommandComplete { credits: 1, opcode, status: 0, parameters: vec![] }
+    }
+
+    fn credits(adapter: &ConnectedAdapter) -> u8 {
+        adapter.commands.lock().unwrap().credits
+    }
+
+    #[test]
+    fn test_answer_to_unsent_command() {
+        let adapter = detached_adapter();
+        let answers = sent_command(&adapter, 0x2005);
+
+        adapter.handle(command_complete(0x200b));
+        adapter.handle(command_complete(0));
+        assert!(answers.try_recv().is_err());
+        assert_eq!(credits(&adapter), 0);
+
+        adapter.handle(command_complete(0x2005));
+        assert_eq!(answers.try_recv().unwrap().unwrap(), Some(vec![]));
+        assert_eq!(credits(&adapter), 1);
+    }
+
+    #[test]
+    fn test_answer_to_foreign_command() {
+        let adapter = detached_adapter();
+
+        // another socket sent the same command before we did, so the first answer is theirs
+        adapter.foreign_command_sent(0x2005);
+        let answers = sent_command(&adapter, 0x2005);
+        adapter.handle(command_complete(0x2005));
+        assert!(answers.try_recv().is_err());
+        assert_eq!(credits(&adapter), 0);
+        adapter.handle(command_complete(0x2005));
+        assert!(answers.try_recv().unwrap().is_ok());
+        assert_eq!(credits(&adapter), 1);
+
+        // and when we send ours first, the first answer is ours
+        let answers = sent_command(&adapter, 0x2005);
+        adapter.foreign_command_sent(0x2005);
+        adapter.handle(command_complete(0x2005));
+        assert!(answers.try_recv().unwrap().is_ok());
+        assert_eq!(adapter.commands.lock().unwrap().foreign.len(), 1);
+    }
+}
+
 
 #[derive(Copy, Debug)]
 #[repr(C)]
@@ -163,6 +324,73 @@ impl AdapterState {
     }
 }
 
+// the all_phys, tx_phys and rx_phys parameters of the PHY commands
+fn phy_preferences(tx: PhyFlags, rx: PhyFlags) -> [u8; 3] {
+    let no_preference = (if tx.is_empty() { 0x01 } else { 0 }) |
+        (if rx.is_empty() { 0x02 } else { 0 });
+    [no_preference, tx.bits(), rx.bits()]
+}
+
+// the error for a command that the controller answered with a failure status
+fn status_error(status: u8) -> Error {
+    match hci::HCIStatus::from_u8(status) {
+        Some(status) => Error::Hci(status),
+        None => Error::Other(format!("Command failed with unknown status 0x{:02X}", status)),
+    }
+}
+
+// an HCI command, and the callback for its return parameters, or for None if the controller
+// answers with a Command Status event instead
+struct QueuedCommand {
+    id: u64,
+    opcode: u16,
+    // where the command falls among all those sent to the controller, once it has been sent
+    sequence: u64,
+    packet: BytesMut,
+    callback: Option<Callback<Option<Vec<u8>>>>,
+}
+
+// commands waiting for the controller to have room for them, and those it has yet to answer
+struct CommandQueue {
+    credits: u8,
+    next_id: u64,
+    next_sequence: u64,
+    waiting: VecDeque<QueuedCommand>,
+    sent: Vec<QueuedCommand>,
+    // the sequence numbers and opcodes of commands other sockets have sent, which the
+    // controller has yet to answer
+    foreign: VecDeque<(u64, u16)>,
+}
+
+// how many commands from other sockets we keep track of, in case some answers go missing
+const MAX_FOREIGN_COMMANDS: usize = 16;
+
+/// Options for `ConnectedAdapter::initialize`, choosing which of the optional events the
+/// controller sends.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct InitOptions {
+    /// Whether to reset the controller first, which drops its connections and stops scanning.
+    pub reset: bool,
+    /// Whether to receive LE PHY Update Complete events, needed for `CentralEvent::PhyUpdated`.
+    pub phy_updates: bool,
+    /// Whether to receive LE Data Length Change events, needed for
+    /// `CentralEvent::DataLengthChanged`.
+    pub data_length_changes: bool,
+}
+
+impl Default for InitOptions {
+    fn default() -> Self {
+        InitOptions {
+            reset: true,
+            phy_updates: true,
+            data_length_changes: true,
+        }
+    }
+}
+
+// the device, its address type and the handle of the link made by a white list connection
+type WhitelistConnection = Result<(BDAddr, AddressType, u16)>;
+
 /// The [`Central`](../../api/trait.Central.html) implementation for BlueZ.
 #[derive(Clone)]
 pub struct ConnectedAdapter {
@@ -170,11 +398,20 @@ pub struct ConnectedAdapter {
     adapter_fd: i32,
     should_stop: Arc<AtomicBool>,
     pub scan_enabled: Arc<AtomicBool>,
-    pub active: Arc<AtomicBool>,
-    pub filter_duplicates: Arc<AtomicBool>,
+    scan_params: Arc<Mutex<ScanParameters>>,
+    scan_filter: Arc<Mutex<Option<ScanFilter>>>,
     peripherals: Arc<Mutex<HashMap<BDAddr, Peripheral>>>,
+    // what devices we've heard from that haven't matched the scan filter yet have advertised
+    unmatched: Arc<Mutex<HashMap<BDAddr, AdvertisingState>>>,
     handle_map: Arc<Mutex<HashMap<u16, BDAddr>>>,
     event_handlers: Arc<Mutex<Vec<EventHandler>>>,
+    timeout: Arc<Mutex<Duration>>,
+    commands: Arc<Mutex<CommandQueue>>,
+    // where to send the outcome of a white list connection
+    whitelist_connection: Arc<Mutex<Option<Sender<WhitelistConnection>>>>,
+    // devices we're connecting to directly, rather than through the white list
+    connecting: Arc<Mutex<HashSet<BDAddr>>>,
+    expiry: Arc<Mutex<Option<(Duration, bool)>>>,
 }
 
 impl ConnectedAdapter {
@@ -194,32 +431,64 @@ impl ConnectedAdapter {
                        std::mem::size_of::<SockaddrHCI>() as u32)
         })?;
 
-        let should_stop = Arc::new(AtomicBool::new(false));
+        let connected = ConnectedAdapter::with_fd(adapter, adapter_fd);
+
+        connected.enable_packet_direction()?;
+        connected.add_raw_socket_reader(adapter_fd);
+        connected.add_expiry_checker();
 
-        let connected = ConnectedAdapter {
+        connected.set_socket_filter()?;
+
+        Ok(connected)
+    }
+
+    // the state of an adapter around an HCI socket, before anything is read from it
+    fn with_fd(adapter: &Adapter, adapter_fd: i32) -> ConnectedAdapter {
+        ConnectedAdapter {
             adapter: adapter.clone(),
             adapter_fd,
-            active: Arc::new(AtomicBool::new(false)),
-            filter_duplicates: Arc::new(AtomicBool::new(false)),
-            should_stop,
+            scan_params: Arc::new(Mutex::new(ScanParameters::default())),
+            scan_filter: Arc::new(Mutex::new(None)),
+            should_stop: Arc::new(AtomicBool::new(false)),
             scan_enabled: Arc::new(AtomicBool::new(false)),
             event_handlers: Arc::new(Mutex::new(vec![])),
             peripherals: Arc::new(Mutex::new(HashMap::new())),
+            unmatched: Arc::new(Mutex::new(HashMap::new())),
             handle_map: Arc::new(Mutex::new(HashMap::new())),
-        };
-
-        connected.add_raw_socket_reader(adapter_fd);
+            timeout: Arc::new(Mutex::new(Duration::from_secs(DEFAULT_TIMEOUT_SECS))),
+            // the controller can take a single command until it tells us otherwise
+            commands: Arc::new(Mutex::new(CommandQueue {
+                credits: 1,
+                next_id: 0,
+                next_sequence: 0,
+                waiting: VecDeque::new(),
+                sent: vec![],
+                foreign: VecDeque::new(),
+            })),
+            whitelist_connection: Arc::new(Mutex::new(None)),
+            connecting: Arc::new(Mutex::new(HashSet::new())),
+            expiry: Arc::new(Mutex::new(None)),
+        }
+    }
 
-        connected.set_socket_filter()?;
+    /// Sets how long synchronous operations on this adapter's peripherals wait for the device
+    /// before failing with `Error::TimedOut`. Defaults to 30 seconds. Operations with a
+    /// `_timeout` variant can override this for a single call.
+    pub fn set_timeout(&self, timeout: Duration) {
+        *self.timeout.lock().unwrap() = timeout;
+    }
 
-        Ok(connected)
+    /// Returns how long synchronous operations wait for the device by default.
+    pub fn timeout(&self) -> Duration {
+        *self.timeout.lock().unwrap()
     }
 
     fn set_socket_filter(&self) -> Result<()> {
         let mut filter = BytesMut::with_capacity(14);
         let type_mask = (1 << HCI_COMMAND_PKT) | (1 << HCI_EVENT_PKT) | (1 << HCI_ACLDATA_PKT);
         let event_mask1 = (1 << EVT_DISCONN_COMPLETE) | (1 << EVT_ENCRYPT_CHANGE) |
-            (1 << EVT_CMD_COMPLETE) | (1 << EVT_CMD_STATUS);
+            (1 << EVT_READ_REMOTE_VERSION_COMPLETE) | (1 << EVT_CMD_COMPLETE) |
+            (1 << EVT_CMD_STATUS);
         let event_mask2 = 1 << (EVT_LE_META_EVENT - 32);
         let opcode = 0;
 
@@ -236,6 +505,47 @@ impl ConnectedAdapter {
         Ok(())
     }
 
+    // the socket sees the packets we send to the controller as well as those it sends us, so
+    // have it say which way each one went
+    fn enable_packet_direction(&self) -> Result<()> {
+        let enable: i32 = 1;
+        handle_error(unsafe {
+            libc::setsockopt(self.adapter_fd, SOL_HCI, HCI_DATA_DIR,
+                             &enable as *const _ as *const libc::c_void,
+                             std::mem::size_of::<i32>() as u32)
+        })?;
+        Ok(())
+    }
+
+    // reads a single packet from the socket, along with whether it came from the controller
+    fn read_packet(fd: i32, buf: &mut [u8]) -> Result<(usize, bool)> {
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        // u64s keep the control messages aligned
+        let mut control = [0u64; 8];
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = std::mem::size_of_val(&control) as _;
+
+        let len = handle_error(unsafe { libc::recvmsg(fd, &mut msg, 0) as i32 })? as usize;
+
+        let mut incoming = true;
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == SOL_HCI && (*cmsg).cmsg_type == HCI_CMSG_DIR {
+                    incoming = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const i32) != 0;
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+        Ok((len, incoming))
+    }
+
     fn add_raw_socket_reader(&self, fd: i32) {
         let should_stop = self.should_stop.clone();
         let connected = self.clone();
@@ -246,13 +556,21 @@ impl ConnectedAdapter {
 
             while !should_stop.load(Ordering::Relaxed) {
                 // debug!("reading");
-                let len = handle_error(unsafe {
-                    libc::read(fd, buf.as_mut_ptr() as *mut _ as *mut libc::c_void, buf.len()) as i32
-                }).unwrap_or(0) as usize;
+                let (len, incoming) = ConnectedAdapter::read_packet(fd, &mut buf)
+                    .unwrap_or((0, true));
                 if len == 0 {
                     continue;
                 }
 
+                if !incoming {
+                    // a copy of something another socket sent to the controller, as the kernel
+                    // doesn't hand us our own
+                    if len >= 3 && buf[0] == HCI_COMMAND_PKT {
+                        connected.foreign_command_sent(u16::from(buf[1]) | u16::from(buf[2]) << 8);
+                    }
+                    continue;
+                }
+
                 cur.put_slice(&buf[0..len]);
 
                 let mut new_cur: Option<Vec<u8>> = Some(vec![]);
@@ -282,6 +600,83 @@ impl ConnectedAdapter {
         });
     }
 
+    fn add_expiry_checker(&self) {
+        let should_stop = self.should_stop.clone();
+        let connected = self.clone();
+
+        thread::spawn(move || {
+            while !should_stop.load(Ordering::Relaxed) {
+                thread::sleep(Duration::from_millis(EXPIRY_CHECK_INTERVAL_MS));
+                connected.forget_unmatched();
+                connected.expire_devices();
+            }
+        });
+    }
+
+    fn expire_devices(&self) {
+        let (timeout, evict) = match *self.expiry.lock().unwrap() {
+            Some(expiry) => expiry,
+            None => return,
+        };
+
+        let now = Instant::now();
+        let lost: Vec<BDAddr> = {
+            let mut peripherals = self.peripherals.lock().unwrap();
+            let lost: Vec<BDAddr> = peripherals.iter()
+                .filter(|&(_, p)| p.expire(timeout, now))
+                .map(|(a, _)| *a)
+                .collect();
+
+            if evict {
+                lost.iter().for_each(|a| { peripherals.remove(a); });
+            }
+            lost
+        };
+
+        // handlers may call back into us, so they can't be called while we hold the lock
+        lost.into_iter().for_each(|a| self.emit(CentralEvent::DeviceLost(a)));
+    }
+
+    // stops tracking devices that haven't matched the scan filter and have gone quiet
+    fn forget_unmatched(&self) {
+        let timeout = Duration::from_secs(UNMATCHED_DEVICE_TIMEOUT_SECS);
+        let now = Instant::now();
+        self.unmatched.lock().unwrap().retain(|_, state| !state.is_quiet(timeout, now));
+    }
+
+    // applies a report to a device that hasn't been discovered yet, returning the device once it
+    // matches the scan filter; the filter is checked against everything the device has
+    // advertised, as what it looks for may be split between the advertisement and scan response
+    fn filter_report(&self, info: &hci::LEAdvertisingInfo) -> Option<Peripheral> {
+        let filter = self.scan_filter.lock().unwrap();
+        let filter = match *filter {
+            Some(ref filter) => filter,
+            None => {
+                let mut advertising = AdvertisingState::default();
+                advertising.apply(info);
+                return Some(Peripheral::with_advertising(self.clone(), info.bdaddr, advertising));
+            }
+        };
+
+        // a device's address doesn't change, so there's no need to keep track of one it rules out
+        if !filter.addresses.is_empty() && !filter.addresses.contains(&info.bdaddr) {
+            return None;
+        }
+
+        let mut unmatched = self.unmatched.lock().unwrap();
+        let matches = {
+            let advertising = unmatched.entry(info.bdaddr).or_default();
+            advertising.apply(info);
+            filter.matches(advertising.properties())
+        };
+
+        if !matches {
+            return None;
+        }
+        unmatched.remove(&info.bdaddr)
+            .map(|advertising| Peripheral::with_advertising(self.clone(), info.bdaddr, advertising))
+    }
+
     fn emit(&self, event: CentralEvent) {
         debug!("emitted {:?}", event);
         let handlers = self.event_handlers.clone();
@@ -295,48 +690,120 @@ impl ConnectedAdapter {
         debug!("got message {:?}", message);
 
         match message {
-            hci::Message::LEAdvertisingReport(info) => {
-                let mut new = false;
-                let address = info.bdaddr.clone();
-
-                {
-                    let mut peripherals = self.peripherals.lock().unwrap();
-                    let peripheral = peripherals.entry(info.bdaddr)
-                        .or_insert_with(|| {
-                            new = true;
-                            Peripheral::new(self.clone(), info.bdaddr)
-                        });
-
+            hci::Message::LEAdvertisingReport(reports) => {
+                for info in reports {
+                    let address = info.bdaddr;
+
+                    let new = {
+                        let mut peripherals = self.peripherals.lock().unwrap();
+                        match peripherals.get(&address) {
+                            // if we'd lost this device, announce it again
+                            Some(peripheral) => peripheral.handle_advertising_report(&info),
+                            None => match self.filter_report(&info) {
+                                Some(peripheral) => {
+                                    peripherals.insert(address, peripheral);
+                                    true
+                                }
+                                None => continue,
+                            },
+                        }
+                    };
 
-                    peripheral.handle_device_message(&hci::Message::LEAdvertisingReport(info));
+                    if new {
+                        self.emit(CentralEvent::DeviceDiscovered(address))
+                    } else {
+                        self.emit(CentralEvent::DeviceUpdated(address))
+                    }
+                }
+            }
+            hci::Message::LEConnComplete(ref info) if info.status != hci::HCIStatus::Success => {
+                info!("connection to {} failed: {:?}", info.bdaddr, info.status);
+                // this has to be checked before the device's own connection attempt can finish
+                let whitelist = self.take_whitelist_connection(info.bdaddr);
+                if let Some(peripheral) = self.peripheral(info.bdaddr) {
+                    peripheral.handle_device_message(&message);
                 }
 
-                if new {
-                    self.emit(CentralEvent::DeviceDiscovered(address.clone()))
-                } else {
-                    self.emit(CentralEvent::DeviceUpdated(address.clone()))
+                if let Some(tx) = whitelist {
+                    let _ = tx.send(Err(Error::Other(
+                        format!("Connection failed: {:?}", info.status))));
                 }
             }
             hci::Message::LEConnComplete(info) => {
                 info!("connected to {:?}", info);
-                let address = info.bdaddr.clone();
-                let handle = info.handle.clone();
-                match self.peripheral(address) {
-                    Some(peripheral) => {
-                        peripheral.handle_device_message(&hci::Message::LEConnComplete(info))
-                    }
-                    // todo: there's probably a better way to handle this case
-                    None => warn!("Got connection for unknown device {}", info.bdaddr)
-                }
+                let address = info.bdaddr;
+                let handle = info.handle;
+                // the device may not have been discovered, so its address type isn't known yet
+                let address_type = AddressType::from_u8(info.bdaddr_type).unwrap_or_default();
+                let peripheral = {
+                    // devices we connect to through the white list may not have been discovered
+                    let mut peripherals = self.peripherals.lock().unwrap();
+                    peripherals.entry(address)
+                        .or_insert_with(|| Peripheral::new(self.clone(), address))
+                        .clone()
+                };
+                // this has to be checked before the device's own connection attempt can finish
+                let whitelist = self.take_whitelist_connection(address);
+                peripheral.handle_device_message(&hci::Message::LEConnComplete(info));
+                self.handle_map.lock().unwrap().insert(handle, address);
 
-                let mut handles = self.handle_map.lock().unwrap();
-                handles.insert(handle, address);
+                if let Some(tx) = whitelist {
+                    let _ = tx.send(Ok((address, address_type, handle)));
+                }
 
                 self.emit(CentralEvent::DeviceConnected(address));
-            }
-            hci::Message::ACLDataPacket(data) => {
-                let message = hci::Message::ACLDataPacket(data);
 
+                // find out what the device supports, so it's known before anyone asks
+                self.read_remote_features(handle, None);
+                self.read_remote_version(handle, None);
+            }
+            hci::Message::ACLDataPacket(ref data) if data.cid == LE_SIGNALING_CID => {
+                self.handle_signaling(data);
+            }
+            hci::Message::LEReadRemoteUsedFeaturesComplete { handle, .. } |
+            hci::Message::ReadRemoteVersionComplete { handle, .. } => {
+                let address = self.handle_map.lock().unwrap().get(&handle).cloned();
+                match address.and_then(|addr| self.peripheral(addr)) {
+                    Some(peripheral) => peripheral.handle_device_message(&message),
+                    None => warn!("got remote information for unknown handle {}", handle),
+                }
+            }
+            hci::Message::LEDataLengthChange { handle, .. } => {
+                let address = self.handle_map.lock().unwrap().get(&handle).cloned();
+                match address.and_then(|addr| self.peripheral(addr)) {
+                    Some(peripheral) => {
+                        peripheral.handle_device_message(&message);
+                        self.emit(CentralEvent::DataLengthChanged(peripheral.address()));
+                    }
+                    None => warn!("got data length change for unknown handle {}", handle),
+                }
+            }
+            hci::Message::LEPhyUpdate { ref status, handle, .. } => {
+                let address = self.handle_map.lock().unwrap().get(&handle).cloned();
+                match address.and_then(|addr| self.peripheral(addr)) {
+                    Some(peripheral) => {
+                        peripheral.handle_device_message(&message);
+                        if *status == hci::HCIStatus::Success {
+                            self.emit(CentralEvent::PhyUpdated(peripheral.address()));
+                        }
+                    }
+                    None => warn!("got phy update for unknown handle {}", handle),
+                }
+            }
+            hci::Message::LEConnUpdate(ref info) => {
+                let address = self.handle_map.lock().unwrap().get(&info.handle).cloned();
+                match address.and_then(|addr| self.peripheral(addr)) {
+                    Some(peripheral) => {
+                        peripheral.handle_device_message(&message);
+                        if info.status == hci::HCIStatus::Success {
+                            let address = peripheral.address();
+                            self.emit(CentralEvent::ConnectionParametersUpdated(address));
+                        }
+                    }
+                    None => warn!("got connection update for unknown handle {}", info.handle),
+                }
+            }
+            hci::Message::ACLDataPacket(_) | hci::Message::ACLDataContinuation { .. } => {
                 // TODO this is a bit risky from a deadlock perspective (note mutexes are not
                 // reentrant in rust!)
                 let peripherals = self.peripherals.lock().unwrap();
@@ -361,6 +828,20 @@ impl ConnectedAdapter {
                     }
                 }
             }
+            hci::Message::HCICommandComplete { credits, opcode, status, parameters } => {
+                let result = match status {
+                    0 => Ok(Some(parameters)),
+                    status => Err(status_error(status)),
+                };
+                self.command_answered(credits, opcode, result);
+            }
+            hci::Message::CommandStatus { credits, opcode, status } => {
+                let result = match status {
+                    0 => Ok(None),
+                    status => Err(status_error(status)),
+                };
+                self.command_answered(credits, opcode, result);
+            }
             _ => {
                 // skip
             }
@@ -376,25 +857,449 @@ impl ConnectedAdapter {
         Ok(())
     }
 
-    fn set_scan_params(&self) -> Result<()> {
+    /// Brings the adapter's controller into a known state: resets it if asked to, enables the
+    /// events the operating system sets up along with those chosen in `options`, and turns on LE
+    /// host support. Fails with `Error::Hci` if the controller rejects any step. As the operating
+    /// system shares the controller, this is only needed when it hasn't set the controller up
+    /// already, or to enable events it leaves masked.
+    pub fn initialize(&self, options: InitOptions) -> Result<()> {
+        if options.reset {
+            self.command_with_status(hci::CommandType::Reset, &[])?;
+
+            // the controller forgets its scanning and connection state when reset, without
+            // sending disconnect events for the connections it drops
+            self.scan_enabled.store(false, Ordering::Relaxed);
+            let handles: Vec<u16> = self.handle_map.lock().unwrap().keys().cloned().collect();
+            for handle in handles {
+                self.handle(hci::Message::DisconnectComplete {
+                    status: hci::HCIStatus::Success,
+                    handle,
+                    reason: hci::HCIStatus::ConnectionTerminatedByLocalHost,
+                });
+            }
+        }
+
+        let (event_mask, le_event_mask) =
+            hci::event_masks(options.data_length_changes, options.phy_updates);
+        let mut data = BytesMut::with_capacity(8);
+        data.put_u64_le(event_mask);
+        self.command_with_status(hci::CommandType::SetEventMask, &data)?;
+        let mut data = BytesMut::with_capacity(8);
+        data.put_u64_le(le_event_mask);
+        self.command_with_status(hci::CommandType::LESetEventMask, &data)?;
+
+        // LE supported by the host, but not simultaneously with BR/EDR to the same device
+        self.command_with_status(hci::CommandType::WriteLEHostSupported, &[1, 0])?;
+        match self.command_sync(hci::CommandType::ReadLEHostSupported, &[])? {
+            hci::CommandComplete::ReadLEHostSupported { le: 1, .. } => Ok(()),
+            complete => {
+                warn!("LE host support was not enabled: {:?}", complete);
+                Err(Error::Other("Failed to enable LE host support".to_string()))
+            }
+        }
+    }
+
+    /// Reads the identity and capabilities of the adapter's controller. This is a synchronous
+    /// operation.
+    pub fn controller_info(&self) -> Result<ControllerInfo> {
+        let (hci_version, hci_revision, lmp_version, manufacturer, lmp_subversion) =
+            match self.command_sync(hci::CommandType::ReadLocalVersion, &[])? {
+                hci::CommandComplete::ReadLocalVersion {
+                    hci_version, hci_revision, lmp_version, manufacturer, lmp_sub_version
+                } => (hci_version, hci_revision, lmp_version, manufacturer, lmp_sub_version),
+                complete => return Err(ConnectedAdapter::unexpected(complete)),
+            };
+
+        let address = match self.command_sync(hci::CommandType::ReadBDAddr, &[])? {
+            hci::CommandComplete::ReadBDAddr { address } => address,
+            complete => return Err(ConnectedAdapter::unexpected(complete)),
+        };
+
+        let le_features = match self.command_sync(
+            hci::CommandType::LEReadLocalSupportedFeatures, &[])? {
+            hci::CommandComplete::LEReadLocalSupportedFeatures { features, .. } => features,
+            complete => return Err(ConnectedAdapter::unexpected(complete)),
+        };
+
+        let le_states = match self.command_sync(hci::CommandType::LEReadSupportedStates, &[])? {
+            hci::CommandComplete::LEReadSupportedStates { states, .. } => states,
+            complete => return Err(ConnectedAdapter::unexpected(complete)),
+        };
+
+        let (acl_packet_length, acl_packets) =
+            match self.command_sync(hci::CommandType::LEReadBufferSize, &[])? {
+                hci::CommandComplete::LEReadBufferSize { packet_length, packets, .. } => {
+                    (packet_length, packets as u16)
+                }
+                complete => return Err(ConnectedAdapter::unexpected(complete)),
+            };
+
+        // a controller without a separate LE buffer reports zero here and shares the BR/EDR one
+        let (acl_packet_length, acl_packets) = if acl_packet_length == 0 || acl_packets == 0 {
+            match self.command_sync(hci::CommandType::ReadBufferSize, &[])? {
+                hci::CommandComplete::ReadBufferSize { acl_packet_length, acl_packets, .. } => {
+                    (acl_packet_length, acl_packets)
+                }
+                complete => return Err(ConnectedAdapter::unexpected(complete)),
+            }
+        } else {
+            (acl_packet_length, acl_packets)
+        };
+
+        Ok(ControllerInfo {
+            address, hci_version, hci_revision, lmp_version, lmp_subversion, manufacturer,
+            manufacturer_name: company_name(manufacturer),
+            le_features, le_states,
+            whitelist_size: self.read_whitelist_size()?,
+            acl_packet_length, acl_packets,
+        })
+    }
+
+    fn unexpected(complete: hci::CommandComplete) -> Error {
+        warn!("Unexpected command response: {:?}", complete);
+        Error::Other(format!("Unexpected response {:?}", complete))
+    }
+
+    /// Sends an HCI command, such as a vendor-specific one, to the adapter and waits for the
+    /// adapter to answer it. Returns the return parameters that follow the status, which are
+    /// empty if the adapter answers with a Command Status event. Fails with `Error::Hci` if the
+    /// adapter rejects the command.
+    pub fn send_command(&self, opcode: u16, params: &[u8]) -> Result<Vec<u8>> {
+        self.command(opcode, params).map(|parameters| parameters.unwrap_or_default())
+    }
+
+    // queues a command to be sent once the controller has room for it, returning its id; this
+    // doesn't wait, so it is safe to use from the thread that reads from the adapter
+    fn queue_command(&self, opcode: u16, data: &[u8],
+                     callback: Option<Callback<Option<Vec<u8>>>>) -> u64 {
+        let (id, failed) = {
+            let mut queue = self.commands.lock().unwrap();
+            let id = queue.next_id;
+            queue.next_id += 1;
+            let packet = hci::hci_command(opcode, data);
+            queue.waiting.push_back(QueuedCommand { id, opcode, sequence: 0, packet, callback });
+            (id, self.send_queued(&mut queue))
+        };
+        ConnectedAdapter::fail_commands(failed);
+        id
+    }
+
+    // sends waiting commands for as long as the controller has room, returning any that failed
+    fn send_queued(&self, queue: &mut CommandQueue) -> Vec<(QueuedCommand, Error)> {
+        let mut failed = vec![];
+        while queue.credits > 0 {
+            let mut command = match queue.waiting.pop_front() {
+                Some(command) => command,
+                None => break,
+            };
+
+            match self.write(&mut command.packet) {
+                Ok(()) => {
+                    queue.credits -= 1;
+                    command.sequence = queue.next_sequence;
+                    queue.next_sequence += 1;
+                    queue.sent.push(command);
+                }
+                Err(e) => failed.push((command, e)),
+            }
+        }
+        failed
+    }
+
+    fn fail_commands(failed: Vec<(QueuedCommand, Error)>) {
+        for (command, err) in failed {
+            warn!("failed to send command 0x{:04X}: {}", command.opcode, err);
+            if let Some(callback) = command.callback {
+                callback(Err(err));
+            }
+        }
+    }
+
+    // notes a command that another socket (or the kernel) sent to the controller, so that its
+    // answer isn't taken for the answer to one of ours
+    fn foreign_command_sent(&self, opcode: u16) {
+        let mut queue = self.commands.lock().unwrap();
+        if queue.foreign.len() == MAX_FOREIGN_COMMANDS {
+            queue.foreign.pop_front();
+        }
+        let sequence = queue.next_sequence;
+        queue.next_sequence += 1;
+        queue.foreign.push_back((sequence, opcode));
+    }
+
+    fn command_answered(&self, credits: u8, opcode: u16, result: Result<Option<Vec<u8>>>) {
+        let (answered, failed) = {
+            let mut queue = self.commands.lock().unwrap();
+
+            // the controller answers commands in the order they were sent, and we see the
+            // answers to every socket's commands, so this one is ours only if we sent a command
+            // with this opcode before anyone else did
+            let ours = queue.sent.iter().position(|c| c.opcode == opcode);
+            let theirs = queue.foreign.iter().position(|&(_, op)| op == opcode);
+            let pos = match (ours, theirs) {
+                (Some(ours), Some(theirs))
+                    if queue.sent[ours].sequence < queue.foreign[theirs].0 => ours,
+                (_, Some(theirs)) => {
+                    queue.foreign.remove(theirs);
+                    return;
+                }
+                (Some(ours), None) => ours,
+                // including an opcode of 0, which the controller sends without being asked
+                (None, None) => return,
+            };
+
+            let answered = queue.sent.remove(pos);
+            queue.credits = credits;
+            (answered, self.send_queued(&mut queue))
+        };
+
+        if let Some(callback) = answered.callback {
+            callback(result);
+        }
+        ConnectedAdapter::fail_commands(failed);
+    }
+
+    // sends a command to the controller and waits for it to be answered
+    fn command(&self, opcode: u16, data: &[u8]) -> Result<Option<Vec<u8>>> {
+        let (tx, rx) = channel();
+        let id = self.queue_command(opcode, data, Some(Box::new(move |result| {
+            let _ = tx.send(result);
+        })));
+
+        let timeout = self.timeout();
+        match rx.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(_) => {
+                // assume the answer was lost, so that it doesn't hold up later commands
+                let failed = {
+                    let mut queue = self.commands.lock().unwrap();
+                    if let Some(pos) = queue.sent.iter().position(|c| c.id == id) {
+                        queue.sent.remove(pos);
+                        queue.credits = queue.credits.max(1);
+                    }
+                    queue.waiting.retain(|c| c.id != id);
+                    self.send_queued(&mut queue)
+                };
+                ConnectedAdapter::fail_commands(failed);
+                Err(Error::TimedOut(timeout))
+            }
+        }
+    }
+
+    // sends a command to the controller and waits for it to complete
+    fn command_sync(&self, command: hci::CommandType, data: &[u8]) -> Result<hci::CommandComplete> {
+        match self.command(command as u16, data)? {
+            Some(parameters) => match hci::command_complete(command as u16, 0, &parameters) {
+                Ok((_, complete)) => Ok(complete),
+                Err(err) => Err(Error::Other(format!("failed to parse response to {:?}: {:?}",
+                                                     command, err))),
+            },
+            None => Err(Error::Other(format!("{:?} did not complete", command))),
+        }
+    }
+
+    // sends a command that returns nothing but its status
+    fn command_with_status(&self, command: hci::CommandType, data: &[u8]) -> Result<()> {
+        self.command(command as u16, data).map(|_| ())
+    }
+
+    // disconnects a link that can no longer be used without waiting for the controller; if it
+    // refuses, the connection is torn down locally so that it isn't left half open
+    fn drop_link(&self, handle: u16) {
+        let mut data = BytesMut::with_capacity(3);
+        data.put_u16_le(handle);
+        data.put_u8(HCI_OE_USER_ENDED_CONNECTION);
+
+        let adapter = self.clone();
+        self.queue_command(hci::CommandType::Disconnect as u16, &data,
+                           Some(Box::new(move |result| {
+            if let Err(err) = result {
+                warn!("failed to disconnect handle {}: {:?}", handle, err);
+                adapter.handle(hci::Message::DisconnectComplete {
+                    status: hci::HCIStatus::Success,
+                    handle,
+                    reason: hci::HCIStatus::ConnectionTerminatedByLocalHost,
+                });
+            }
+        })));
+    }
+
+    // records a connection attempt to the device; the controller can only create one connection
+    // at a time, so this fails while connecting through the white list
+    fn begin_connection(&self, address: BDAddr) -> Result<ConnectionAttempt> {
+        let whitelist = self.whitelist_connection.lock().unwrap();
+        if whitelist.is_some() {
+            return Err(Error::Other("A white list connection is in progress".to_string()));
+        }
+
+        self.connecting.lock().unwrap().insert(address);
+        Ok(ConnectionAttempt { adapter: self.clone(), address })
+    }
+
+    fn is_connecting(&self, address: BDAddr) -> bool {
+        self.connecting.lock().unwrap().contains(&address)
+    }
+
+    // returns the sender for the white list connection in progress, unless the connection to the
+    // given device was made by a direct connection attempt instead
+    fn take_whitelist_connection(&self, address: BDAddr) -> Option<Sender<WhitelistConnection>> {
+        let mut whitelist = self.whitelist_connection.lock().unwrap();
+        if self.connecting.lock().unwrap().contains(&address) {
+            return None;
+        }
+        whitelist.take()
+    }
+
+    // starts connecting to the given device, or to any device on the white list if there is none;
+    // the result arrives as an LE Connection Complete event
+    pub fn create_connection(&self, peer: Option<(BDAddr, AddressType)>,
+                             params: &ConnectionParameters) -> Result<()> {
+        if !hci::valid_scan_window(params.scan_interval, params.scan_window) {
+            return Err(Error::Other(format!("invalid scan parameters: interval {}, window {}",
+                                            params.scan_interval, params.scan_window)));
+        }
+
+        if !hci::valid_connection_parameters(params.min_interval, params.max_interval,
+                                             params.latency, params.supervision_timeout) {
+            return Err(Error::Other(format!(
+                "invalid connection parameters: interval {}-{}, latency {}, timeout {}",
+                params.min_interval, params.max_interval, params.latency,
+                params.supervision_timeout)));
+        }
+
+        let mut data = BytesMut::with_capacity(25);
+        data.put_u16_le(params.scan_interval); // scan interval, 0.625 ms units
+        data.put_u16_le(params.scan_window); // scan window, 0.625 ms units
+        match peer {
+            Some((address, address_type)) => {
+                data.put_u8(0); // initiator filter policy = peer address
+                data.put_u8(address_type.num()); // peer address type
+                data.put_slice(&address.address); // peer address
+            }
+            None => {
+                data.put_u8(1); // initiator filter policy = white list
+                data.put_u8(0); // peer address type, ignored
+                data.put_slice(&[0u8; 6]); // peer address, ignored
+            }
+        }
+        data.put_u8(0); // own address type = public
+        data.put_u16_le(params.min_interval); // min connection interval, 1.25 ms units
+        data.put_u16_le(params.max_interval); // max connection interval, 1.25 ms units
+        data.put_u16_le(params.latency); // slave latency
+        data.put_u16_le(params.supervision_timeout); // supervision timeout, 10 ms units
+        data.put_u16_le(0x0000); // min CE length
+        data.put_u16_le(0x0000); // max CE length
+        self.command_with_status(hci::CommandType::LECreateConnection, &data)
+    }
+
+    // aborts the connection attempt in progress, which then completes with an error status
+    pub fn cancel_connection(&self) -> Result<()> {
+        self.command_with_status(hci::CommandType::LECreateConnectionCancel, &[])
+    }
+
+    pub fn update_connection(&self, handle: u16, min_interval: u16, max_interval: u16,
+                             latency: u16, timeout: u16) -> Result<()> {
+        if !hci::valid_connection_parameters(min_interval, max_interval, latency, timeout) {
+            return Err(Error::Other(format!(
+                "invalid connection parameters: interval {}-{}, latency {}, timeout {}",
+                min_interval, max_interval, latency, timeout)));
+        }
+
+        let data = ConnectedAdapter::connection_update_data(
+            handle, min_interval, max_interval, latency, timeout);
+        self.command_with_status(hci::CommandType::LEConnectionUpdate, &data)
+    }
+
+    fn connection_update_data(handle: u16, min_interval: u16, max_interval: u16, latency: u16,
+                              timeout: u16) -> BytesMut {
+        let mut data = BytesMut::with_capacity(14);
+        data.put_u16_le(handle);
+        data.put_u16_le(min_interval); // min connection interval, 1.25 ms units
+        data.put_u16_le(max_interval); // max connection interval, 1.25 ms units
+        data.put_u16_le(latency); // slave latency
+        data.put_u16_le(timeout); // supervision timeout, 10 ms units
+        data.put_u16_le(0x0000); // min CE length
+        data.put_u16_le(0x0000); // max CE length
+        data
+    }
+
+    // the result arrives as an LE PHY Update Complete event
+    pub fn set_phy(&self, handle: u16, tx: PhyFlags, rx: PhyFlags,
+                   options: CodedPhyOptions) -> Result<()> {
+        let mut data = BytesMut::with_capacity(7);
+        data.put_u16_le(handle);
+        data.put_slice(&phy_preferences(tx, rx));
+        data.put_u16_le(options.num());
+        self.command_with_status(hci::CommandType::LESetPHY, &data)
+    }
+
+    // the result arrives as an LE Read Remote Features Complete event; the callback is only
+    // called if the command can't be sent or is rejected
+    pub fn read_remote_features(&self, handle: u16, on_error: Option<CommandCallback>) {
+        let mut data = BytesMut::with_capacity(2);
+        data.put_u16_le(handle);
+        self.queue_command(hci::CommandType::LEReadRemoteUsedFeatures as u16, &data,
+                           ConnectedAdapter::error_callback(on_error));
+    }
+
+    // the result arrives as a Read Remote Version Information Complete event; the callback is
+    // only called if the command can't be sent or is rejected
+    pub fn read_remote_version(&self, handle: u16, on_error: Option<CommandCallback>) {
+        let mut data = BytesMut::with_capacity(2);
+        data.put_u16_le(handle);
+        self.queue_command(hci::CommandType::ReadRemoteVersionInformation as u16, &data,
+                           ConnectedAdapter::error_callback(on_error));
+    }
+
+    fn error_callback(on_error: Option<CommandCallback>) -> Option<Callback<Option<Vec<u8>>>> {
+        on_error.map(|on_error| -> Callback<Option<Vec<u8>>> {
+            Box::new(move |result: Result<Option<Vec<u8>>>| {
+                if let Err(e) = result {
+                    on_error(Err(e));
+                }
+            })
+        })
+    }
+
+    // handles a command from a device on the LE signaling channel. The kernel answers connection
+    // parameter update requests itself, and if it accepts one the controller reports the new
+    // parameters with an LE Connection Update Complete event, just as for our own updates.
+    fn handle_signaling(&self, data: &hci::ACLData) {
+        match l2cap::signaling_command(&data.data) {
+            Ok((_, l2cap::SignalingCommand::ConnectionParameterUpdateRequest {
+                min_interval, max_interval, latency, timeout, ..
+            })) => {
+                debug!("handle {} requested connection interval {}-{}, latency {}, timeout {} \
+                        (valid: {})", data.handle, min_interval, max_interval, latency, timeout,
+                       hci::valid_connection_parameters(min_interval, max_interval, latency,
+                                                        timeout));
+            }
+            Ok((_, command)) => debug!("ignoring signaling command {:?}", command),
+            Err(err) => warn!("failed to parse signaling command {:?}: {:?}", data.data, err),
+        }
+    }
+
+    fn whitelist_command(&self, command: hci::CommandType, address: BDAddr,
+                         address_type: AddressType) -> Result<()> {
         let mut data = BytesMut::with_capacity(7);
-        data.put_u8(if self.active.load(Ordering::Relaxed) { 1 } else { 0 }); // scan_type = active or passive
-        data.put_u16_le(0x0010); // interval ms
-        data.put_u16_le(0x0010); // window ms
-        data.put_u8(0); // own_type = public
-        data.put_u8(0); // filter_policy = public
-        let mut buf = hci::hci_command(LE_SET_SCAN_PARAMETERS_CMD, &*data);
-        self.write(&mut *buf)
+        data.put_u8(address_type.num());
+        data.put_slice(&address.address);
+        self.command_with_status(command, &data)
+    }
+
+    fn set_scan_params(&self) -> Result<()> {
+        let data = hci::le_scan_parameters(&self.scan_parameters());
+        self.command_with_status(hci::CommandType::LESetScanParameters, &data)
     }
 
     fn set_scan_enabled(&self, enabled: bool) -> Result<()> {
         let mut data = BytesMut::with_capacity(2);
         data.put_u8(if enabled { 1 } else { 0 }); // enabled
-        data.put_u8(if self.filter_duplicates.load(Ordering::Relaxed) { 1 } else { 0 }); // filter duplicates
+        data.put_u8(if self.scan_parameters().filter_duplicates { 1 } else { 0 }); // filter duplicates
 
+        self.command_with_status(hci::CommandType::LESetScanEnabled, &data)?;
         self.scan_enabled.clone().store(enabled, Ordering::Relaxed);
-        let mut buf = hci::hci_command(LE_SET_SCAN_ENABLE_CMD, &*data);
-        self.write(&mut *buf)
+        Ok(())
     }
 }
 
@@ -405,18 +1310,127 @@ impl Central<Peripheral> for ConnectedAdapter {
     }
 
     fn active(&self, enabled: bool) {
-        self.active.clone().store(enabled, Ordering::Relaxed);
+        self.scan_params.lock().unwrap().active = enabled;
     }
 
     fn filter_duplicates(&self, enabled: bool) {
-        self.filter_duplicates.clone().store(enabled, Ordering::Relaxed);
+        self.scan_params.lock().unwrap().filter_duplicates = enabled;
     }
 
     fn start_scan(&self) -> Result<()> {
+        // the controller won't accept new parameters while it's scanning
+        if self.scan_enabled.load(Ordering::Relaxed) {
+            self.set_scan_enabled(false)?;
+        }
+
         self.set_scan_params()?;
         self.set_scan_enabled(true)
     }
 
+    fn start_scan_with(&self, params: ScanParameters) -> Result<()> {
+        if !hci::valid_scan_window(params.interval, params.window) {
+            return Err(Error::Other(format!("invalid scan interval {} and window {}",
+                                            params.interval, params.window)));
+        }
+
+        *self.scan_params.lock().unwrap() = params;
+        self.start_scan()
+    }
+
+    fn scan_parameters(&self) -> ScanParameters {
+        *self.scan_params.lock().unwrap()
+    }
+
+    fn set_scan_filter(&self, filter: Option<ScanFilter>) {
+        *self.scan_filter.lock().unwrap() = filter;
+        self.unmatched.lock().unwrap().clear();
+    }
+
+    fn set_device_expiry(&self, timeout: Option<Duration>, evict: bool) {
+        *self.expiry.lock().unwrap() = timeout.map(|t| (t, evict));
+    }
+
+    fn whitelist_add(&self, address: BDAddr, address_type: AddressType) -> Result<()> {
+        self.whitelist_command(hci::CommandType::LEAddDeviceToWhiteList, address, address_type)
+    }
+
+    fn whitelist_remove(&self, address: BDAddr, address_type: AddressType) -> Result<()> {
+        self.whitelist_command(hci::CommandType::LERemoveDeviceFromWhiteList, address,
+                               address_type)
+    }
+
+    fn whitelist_clear(&self) -> Result<()> {
+        self.command_with_status(hci::CommandType::LEClearWhiteList, &[])
+    }
+
+    fn set_default_phy(&self, tx: PhyFlags, rx: PhyFlags) -> Result<()> {
+        self.command_with_status(hci::CommandType::LESetDefaultPHY, &phy_preferences(tx, rx))
+    }
+
+    fn read_suggested_data_length(&self) -> Result<(u16, u16)> {
+        match self.command_sync(hci::CommandType::LEReadSuggestedDefaultDataLength, &[])? {
+            hci::CommandComplete::LEReadSuggestedDefaultDataLength {
+                status: 0, tx_octets, tx_time
+            } => Ok((tx_octets, tx_time)),
+            complete => {
+                warn!("Unexpected read suggested data length response: {:?}", complete);
+                Err(Error::Other("Failed to read suggested data length".to_string()))
+            }
+        }
+    }
+
+    fn read_maximum_data_length(&self) -> Result<DataLength> {
+        match self.command_sync(hci::CommandType::LEReadMaximumDataLength, &[])? {
+            hci::CommandComplete::LEReadMaximumDataLength { status: 0, lengths } => Ok(lengths),
+            complete => {
+                warn!("Unexpected read maximum data length response: {:?}", complete);
+                Err(Error::Other("Failed to read maximum data length".to_string()))
+            }
+        }
+    }
+
+    fn read_whitelist_size(&self) -> Result<u8> {
+        match self.command_sync(hci::CommandType::LEReadWhiteListSize, &[])? {
+            hci::CommandComplete::LEReadWhiteListSize { status: 0, size } => Ok(size),
+            complete => {
+                warn!("Unexpected read white list size response: {:?}", complete);
+                Err(Error::Other("Failed to read white list size".to_string()))
+            }
+        }
+    }
+
+    fn connect_whitelist(&self) -> Result<Peripheral> {
+        let (tx, rx) = channel();
+        {
+            let mut whitelist = self.whitelist_connection.lock().unwrap();
+            if whitelist.is_some() || !self.connecting.lock().unwrap().is_empty() {
+                return Err(Error::Other("A connection is already in progress".to_string()));
+            }
+            *whitelist = Some(tx);
+        }
+
+        if let Err(e) = self.create_connection(None, &ConnectionParameters::default()) {
+            self.whitelist_connection.lock().unwrap().take();
+            return Err(e);
+        }
+
+        let timeout = self.timeout();
+        let (address, address_type, handle) = match rx.recv_timeout(timeout) {
+            Ok(result) => result?,
+            Err(_) => {
+                self.whitelist_connection.lock().unwrap().take();
+                self.cancel_connection()?;
+                return Err(Error::TimedOut(timeout));
+            }
+        };
+
+        // set up our ATT channel over the link the controller has created; the connection has
+        // already been reported, so this mustn't wait for it like `connect` does
+        let peripheral = self.peripheral(address).ok_or(Error::DeviceNotFound)?;
+        peripheral.attach(handle, address_type)?;
+        Ok(peripheral)
+    }
+
     fn stop_scan(&self) -> Result<()> {
         self.set_scan_enabled(false)
     }
@@ -432,6 +1446,18 @@ impl Central<Peripheral> for ConnectedAdapter {
     }
 }
 
+// a connection attempt to a specific device, which is over once this is dropped
+struct ConnectionAttempt {
+    adapter: ConnectedAdapter,
+    address: BDAddr,
+}
+
+impl Drop for ConnectionAttempt {
+    fn drop(&mut self) {
+        self.adapter.connecting.lock().unwrap().remove(&self.address);
+    }
+}
+
 /// Adapter represents a physical bluetooth interface in your system, for example a bluetooth
 /// dongle.
 #[derive(Debug, Clone)]
@@ -1,9 +1,11 @@
 use ::Result;
 
-use api::{Characteristic, CharPropFlags, Callback, PeripheralProperties, BDAddr, Central,
-          Peripheral as ApiPeripheral};
+use api::{Characteristic, CharPropFlags, Callback, PeripheralProperties, BDAddr, Central, Service,
+          Descriptor, ConnectionParameters, ConnectionInfo, LEFeatureFlags, RemoteVersion,
+          Phy, PhyFlags, CodedPhyOptions, DataLength, Peripheral as ApiPeripheral};
 use std::mem::size_of;
-use std::collections::BTreeSet;
+use std::cmp;
+use std::collections::{BTreeSet, HashMap};
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::sync::atomic::Ordering;
@@ -14,14 +16,16 @@ use bluez::adapter::acl_stream::{ACLStream};
 use bluez::adapter::ConnectedAdapter;
 use bluez::util::handle_error;
 use bluez::constants::*;
-use ::Error;
+use ::{Error, AttError};
 use bluez::protocol::hci;
+use bluez::protocol::hci::LEAdvertisingInfo;
 use api::AddressType;
 use std::sync::mpsc;
 use std::sync::mpsc::Sender;
 use std::sync::mpsc::Receiver;
 use std::sync::mpsc::channel;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use std::sync::atomic::AtomicBool;
 use bytes::{BytesMut, BufMut};
 use bluez::protocol::att;
 use std::fmt::Debug;
@@ -34,10 +38,140 @@ use std::sync::Condvar;
 use api::RequestCallback;
 use api::CommandCallback;
 use api::UUID;
-use api::UUID::B16;
+use api::UUID::{B16, B128};
 use api::NotificationHandler;
 use std::fmt::Display;
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bluez::protocol::hci::LEAdvertisingData::*;
+
+    fn report(evt_type: u8, data: Vec<hci::LEAdvertisingData>) -> LEAdvertisingInfo {
+        LEAdvertisingInfo {
+            evt_type,
+            bdaddr_type: 1,
+            bdaddr: BDAddr { address: [1, 2, 3, 4, 5, 6] },
+            data,
+            rssi: -70,
+        }
+    }
+
+    #[test]
+    fn test_connection_results_drop_stale() {
+        let results = ConnectionResults::new();
+        // the outcome of an attempt we stopped waiting for, or of a white list connection
+        results.send(Ok(64));
+        results.clear();
+        results.send(Ok(65));
+        assert_eq!(results.wait(Duration::from_millis(10)).unwrap(), 65);
+    }
+
+    #[test]
+    fn test_connection_results_timeout() {
+        let results = ConnectionResults::new();
+        results.send(Err(Error::Cancelled));
+        results.clear();
+        match results.wait(Duration::from_millis(10)) {
+            Err(Error::TimedOut(_)) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_should_expire() {
+        let timeout = Duration::from_secs(10);
+        let seen = Instant::now();
+        let later = seen + timeout;
+
+        assert!(should_expire(false, false, Some(seen), timeout, later));
+        let earlier = later - Duration::from_millis(1);
+        assert!(!should_expire(false, false, Some(seen), timeout, earlier));
+        // connected devices and those being connected to don't advertise
+        assert!(!should_expire(true, false, Some(seen), timeout, later));
+        // a lost device is only announced once, until it's seen again
+        assert!(!should_expire(false, true, Some(seen), timeout, later));
+        assert!(!should_expire(false, false, None, timeout, later));
+    }
+
+    #[test]
+    fn test_advertising_state_quiet() {
+        let timeout = Duration::from_secs(10);
+        let mut state = AdvertisingState::default();
+        assert!(!state.is_quiet(timeout, Instant::now()));
+
+        state.apply(&report(0, vec![]));
+        let seen = state.properties().last_seen.unwrap();
+        assert!(!state.is_quiet(timeout, seen));
+        assert!(state.is_quiet(timeout, seen + timeout));
+    }
+
+    #[test]
+    fn test_uuid_32() {
+        let mut expected = BLUETOOTH_BASE_UUID;
+        expected[12..].copy_from_slice(&[0xCD, 0xAB, 0x34, 0x12]);
+        assert_eq!(uuid_32(0x1234ABCD), B128(expected));
+        assert_eq!(uuid_32(0x1234ABCD).to_string(),
+                   "12:34:AB:CD:00:00:10:00:80:00:00:80:5F:9B:34:FB");
+    }
+
+    #[test]
+    fn test_from_report() {
+        let mut properties = PeripheralProperties::default();
+        let advertised = AdvertisedData::from_report(&report(0, vec![
+            LocalName("sensor".to_string()),
+            ServiceClassUUID16(0x180D),
+            ServiceClassUUID32(0x1234ABCD),
+            ServiceData16(0x180D, vec![1, 2]),
+            ManufacturerSpecific(0x004C, vec![3]),
+            ManufacturerSpecific(0x0006, vec![4, 5]),
+        ]), &mut properties);
+
+        assert_eq!(properties.local_name, Some("sensor".to_string()));
+        assert_eq!(properties.address_type, AddressType::Random);
+        assert_eq!(properties.rssi, Some(-70));
+        assert_eq!(advertised.services, vec![B16(0x180D), uuid_32(0x1234ABCD)]);
+        assert_eq!(advertised.service_data.get(&B16(0x180D)), Some(&vec![1, 2]));
+        assert_eq!(advertised.manufacturer_data.len(), 2);
+        assert_eq!(advertised.manufacturer_data.get(&0x004C), Some(&vec![3]));
+        assert_eq!(advertised.manufacturer_data.get(&0x0006), Some(&vec![4, 5]));
+
+        let mut report = report(0, vec![]);
+        report.rssi = 127;
+        AdvertisedData::from_report(&report, &mut properties);
+        assert_eq!(properties.rssi, None);
+    }
+
+    #[test]
+    fn test_merge_scan_response() {
+        let mut properties = PeripheralProperties::default();
+        let advertisement = AdvertisedData::from_report(&report(0, vec![
+            ServiceClassUUID16(0x180D),
+            ServiceData16(0x180D, vec![1]),
+            ServiceData16(0x180F, vec![2]),
+            ManufacturerSpecific(0x004C, vec![3]),
+        ]), &mut properties);
+        let scan_response = AdvertisedData::from_report(&report(4, vec![
+            ServiceClassUUID16(0x180D),
+            ServiceClassUUID16(0x180A),
+            ServiceData16(0x180D, vec![4]),
+            ManufacturerSpecific(0x0006, vec![5]),
+        ]), &mut properties);
+
+        advertisement.merge_into(&scan_response, &mut properties);
+
+        // services from both, without repeats
+        assert_eq!(properties.services, vec![B16(0x180D), B16(0x180A)]);
+        // the scan response's data replaces that of the advertisement for the same key
+        assert_eq!(properties.service_data.len(), 2);
+        assert_eq!(properties.service_data.get(&B16(0x180D)), Some(&vec![4]));
+        assert_eq!(properties.service_data.get(&B16(0x180F)), Some(&vec![2]));
+        assert_eq!(properties.manufacturer_data.len(), 2);
+        assert_eq!(properties.manufacturer_data.get(&0x004C), Some(&vec![3]));
+        assert_eq!(properties.manufacturer_data.get(&0x0006), Some(&vec![5]));
+    }
+}
+
 #[derive(Copy, Debug)]
 #[repr(C)]
 pub struct SockaddrL2 {
@@ -66,23 +200,235 @@ impl Clone for L2CapOptions {
     fn clone(&self) -> Self { *self }
 }
 
+// the point at which a synchronous operation stops waiting for the device
+#[derive(Copy, Clone, Debug)]
+struct Deadline {
+    timeout: Duration,
+    at: Instant,
+}
+
+impl Deadline {
+    fn after(timeout: Duration) -> Deadline {
+        Deadline { timeout, at: Instant::now() + timeout }
+    }
+
+    fn remaining(&self) -> Result<Duration> {
+        let now = Instant::now();
+        if now >= self.at {
+            Err(Error::TimedOut(self.timeout))
+        } else {
+            Ok(self.at - now)
+        }
+    }
+}
+
+// the outcomes of the device's connection attempts, as reported by LE Connection Complete events
+struct ConnectionResults {
+    tx: Mutex<Sender<Result<u16>>>,
+    rx: Mutex<Receiver<Result<u16>>>,
+}
+
+impl ConnectionResults {
+    fn new() -> ConnectionResults {
+        let (tx, rx) = channel();
+        ConnectionResults { tx: Mutex::new(tx), rx: Mutex::new(rx) }
+    }
+
+    fn send(&self, result: Result<u16>) {
+        // we hold the receiver ourselves, so this can't fail
+        self.tx.lock().unwrap().send(result).unwrap();
+    }
+
+    // drops the outcomes of earlier attempts that nobody waited for, so that they aren't taken
+    // for the outcome of the attempt about to start
+    fn clear(&self) {
+        let rx = self.rx.lock().unwrap();
+        while rx.try_recv().is_ok() {}
+    }
+
+    fn wait(&self, timeout: Duration) -> Result<u16> {
+        match self.rx.lock().unwrap().recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(Error::TimedOut(timeout)),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                // we hold the sender ourselves, so this can't happen
+                unreachable!();
+            }
+        }
+    }
+}
+
+// the service and manufacturer data from a single advertising packet
+#[derive(Debug, Default)]
+struct AdvertisedData {
+    services: Vec<UUID>,
+    solicited_services: Vec<UUID>,
+    service_data: HashMap<UUID, Vec<u8>>,
+    manufacturer_data: HashMap<u16, Vec<u8>>,
+}
+
+impl AdvertisedData {
+    // reads the data in an advertising report, updating the properties that aren't specific to
+    // the kind of packet it came from
+    fn from_report(info: &LEAdvertisingInfo,
+                   properties: &mut PeripheralProperties) -> AdvertisedData {
+        use bluez::protocol::hci::LEAdvertisingData::*;
+
+        properties.address_type = if info.bdaddr_type == 1 {
+            AddressType::Random
+        } else {
+            AddressType::Public
+        };
+
+        properties.address = info.bdaddr;
+        // 127 means the controller couldn't measure it
+        properties.rssi = if info.rssi == 127 { None } else { Some(info.rssi) };
+
+        let mut advertised = AdvertisedData::default();
+
+        for datum in info.data.iter() {
+            match *datum {
+                LocalName(ref name) => {
+                    properties.local_name = Some(name.clone());
+                }
+                TxPowerLevel(power) => {
+                    properties.tx_power_level = Some(power);
+                }
+                ServiceClassUUID16(uuid) => advertised.services.push(B16(uuid)),
+                ServiceClassUUID32(uuid) => advertised.services.push(uuid_32(uuid)),
+                ServiceClassUUID128(uuid) => advertised.services.push(B128(uuid)),
+                SolicitationUUID16(uuid) => advertised.solicited_services.push(B16(uuid)),
+                SolicitationUUID32(uuid) => advertised.solicited_services.push(uuid_32(uuid)),
+                SolicitationUUID128(uuid) => advertised.solicited_services.push(B128(uuid)),
+                ServiceData16(uuid, ref data) => {
+                    advertised.service_data.insert(B16(uuid), data.clone());
+                }
+                ServiceData32(uuid, ref data) => {
+                    advertised.service_data.insert(uuid_32(uuid), data.clone());
+                }
+                ServiceData128(uuid, ref data) => {
+                    advertised.service_data.insert(B128(uuid), data.clone());
+                }
+                ManufacturerSpecific(company_id, ref data) => {
+                    advertised.manufacturer_data.insert(company_id, data.clone());
+                }
+                _ => {
+                    // skip for now
+                }
+            }
+        }
+
+        advertised
+    }
+
+    // combines this advertising data with that of the scan response
+    fn merge_into(&self, scan_response: &AdvertisedData, properties: &mut PeripheralProperties) {
+        let mut services = self.services.clone();
+        services.extend(scan_response.services.iter().filter(|u| !self.services.contains(u)));
+        properties.services = services;
+
+        let mut solicited = self.solicited_services.clone();
+        solicited.extend(scan_response.solicited_services.iter()
+            .filter(|u| !self.solicited_services.contains(u)));
+        properties.solicited_services = solicited;
+
+        properties.service_data = self.service_data.clone();
+        properties.service_data.extend(scan_response.service_data.iter()
+            .map(|(k, v)| (*k, v.clone())));
+
+        properties.manufacturer_data = self.manufacturer_data.clone();
+        properties.manufacturer_data.extend(scan_response.manufacturer_data.iter()
+            .map(|(k, v)| (*k, v.clone())));
+    }
+}
+
+// expands a 32-bit service UUID using the Bluetooth base UUID
+fn uuid_32(uuid: u32) -> UUID {
+    let mut bytes = BLUETOOTH_BASE_UUID;
+    bytes[12] = uuid as u8;
+    bytes[13] = (uuid >> 8) as u8;
+    bytes[14] = (uuid >> 16) as u8;
+    bytes[15] = (uuid >> 24) as u8;
+    B128(bytes)
+}
+
+/// What we know about a device from its advertising reports.
+#[derive(Debug, Default)]
+pub struct AdvertisingState {
+    properties: PeripheralProperties,
+    advertisement: AdvertisedData,
+    scan_response: AdvertisedData,
+}
+
+impl AdvertisingState {
+    /// Updates the state from an advertising report.
+    pub fn apply(&mut self, info: &LEAdvertisingInfo) {
+        self.properties.discovery_count += 1;
+        self.properties.last_seen = Some(Instant::now());
+
+        // each packet carries the full set of data for its kind, so it replaces whatever we got
+        // from the previous packet of the same kind
+        let advertised = AdvertisedData::from_report(info, &mut self.properties);
+        if info.evt_type == 4 {
+            // scan response
+            self.properties.has_scan_response = true;
+            self.scan_response = advertised;
+        } else {
+            self.advertisement = advertised;
+        }
+
+        self.advertisement.merge_into(&self.scan_response, &mut self.properties);
+    }
+
+    /// The properties of the device, merged from its advertisement and scan response.
+    pub fn properties(&self) -> &PeripheralProperties {
+        &self.properties
+    }
+
+    /// Returns true if we've had no advertising report from the device for at least `timeout`
+    /// at `now`.
+    pub fn is_quiet(&self, timeout: Duration, now: Instant) -> bool {
+        quiet(self.properties.last_seen, timeout, now)
+    }
+}
+
+// whether a device last heard from at `last_seen` has been quiet for at least `timeout` at `now`;
+// there is nothing to time out for a device we've never heard from
+fn quiet(last_seen: Option<Instant>, timeout: Duration, now: Instant) -> bool {
+    last_seen.map(|t| now >= t + timeout).unwrap_or(false)
+}
+
+// whether a device should be reported lost at `now`; a device stops advertising while it is
+// connected or being connected to, so it is never lost then, and one that is already lost isn't
+// reported again until it has been seen
+fn should_expire(busy: bool, lost: bool, last_seen: Option<Instant>, timeout: Duration,
+                 now: Instant) -> bool {
+    !busy && !lost && quiet(last_seen, timeout, now)
+}
+
 #[derive(Clone)]
 pub struct Peripheral {
     c_adapter: ConnectedAdapter,
     address: BDAddr,
-    properties: Arc<Mutex<PeripheralProperties>>,
+    advertising: Arc<Mutex<AdvertisingState>>,
+    lost: Arc<AtomicBool>,
     characteristics: Arc<Mutex<BTreeSet<Characteristic>>>,
+    services: Arc<Mutex<BTreeSet<Service>>>,
     stream: Arc<RwLock<Option<ACLStream>>>,
-    connection_tx: Arc<Mutex<Sender<u16>>>,
-    connection_rx: Arc<Mutex<Receiver<u16>>>,
+    connection_results: Arc<ConnectionResults>,
     message_queue: Arc<Mutex<VecDeque<ACLData>>>,
+    connection_info: Arc<Mutex<Option<ConnectionInfo>>>,
+    remote_features: Arc<Mutex<Option<LEFeatureFlags>>>,
+    remote_version: Arc<Mutex<Option<RemoteVersion>>>,
+    feature_waiters: Arc<Mutex<Vec<Callback<LEFeatureFlags>>>>,
+    version_waiters: Arc<Mutex<Vec<Callback<RemoteVersion>>>>,
 }
 
 impl Display for Peripheral {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         let connected = if self.is_connected() { " connected" } else { "" };
-        let properties = self.properties.lock().unwrap();
-        write!(f, "{} {}{}", self.address, properties.local_name.clone()
+        let advertising = self.advertising.lock().unwrap();
+        write!(f, "{} {}{}", self.address, advertising.properties.local_name.clone()
             .unwrap_or("(unknown)".to_string()), connected)
     }
 }
@@ -90,76 +436,139 @@ impl Display for Peripheral {
 impl Debug for Peripheral {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         let connected = if self.is_connected() { " connected" } else { "" };
-        let properties = self.properties.lock().unwrap();
+        let advertising = self.advertising.lock().unwrap();
         let characteristics = self.characteristics.lock().unwrap();
-        write!(f, "{} properties: {:?}, characteristics: {:?} {}", self.address, *properties,
+        write!(f, "{} properties: {:?}, characteristics: {:?} {}", self.address,
+               advertising.properties,
                *characteristics, connected)
     }
 }
 
 impl Peripheral {
     pub fn new(c_adapter: ConnectedAdapter, address: BDAddr) -> Peripheral {
-        let (connection_tx, connection_rx) = channel();
+        Peripheral::with_advertising(c_adapter, address, AdvertisingState::default())
+    }
+
+    /// Creates a peripheral for a device we've already had advertising reports from.
+    pub fn with_advertising(c_adapter: ConnectedAdapter, address: BDAddr,
+                            advertising: AdvertisingState) -> Peripheral {
         Peripheral {
             c_adapter, address,
-            properties: Arc::new(Mutex::new(PeripheralProperties::default())),
+            advertising: Arc::new(Mutex::new(advertising)),
+            lost: Arc::new(AtomicBool::new(false)),
             characteristics: Arc::new(Mutex::new(BTreeSet::new())),
+            services: Arc::new(Mutex::new(BTreeSet::new())),
             stream: Arc::new(RwLock::new(Option::None)),
-            connection_tx: Arc::new(Mutex::new(connection_tx)),
-            connection_rx: Arc::new(Mutex::new(connection_rx)),
+            connection_results: Arc::new(ConnectionResults::new()),
             message_queue: Arc::new(Mutex::new(VecDeque::new())),
+            connection_info: Arc::new(Mutex::new(None)),
+            remote_features: Arc::new(Mutex::new(None)),
+            remote_version: Arc::new(Mutex::new(None)),
+            feature_waiters: Arc::new(Mutex::new(vec![])),
+            version_waiters: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    /// Updates the peripheral from an advertising report. Returns true if the peripheral had been
+    /// lost before this report.
+    pub fn handle_advertising_report(&self, info: &LEAdvertisingInfo) -> bool {
+        assert_eq!(self.address, info.bdaddr, "received message for wrong device");
+
+        self.advertising.lock().unwrap().apply(info);
+        self.lost.swap(false, Ordering::Relaxed)
+    }
+
+    /// Marks the peripheral as lost if we haven't connected to it or had an advertising report
+    /// from it within `timeout` of `now`. Returns true if the peripheral has just been lost. A
+    /// device stops advertising while we connect to it, so one that is being connected to is never
+    /// lost.
+    pub fn expire(&self, timeout: Duration, now: Instant) -> bool {
+        // the link may be up before the ATT channel over it is
+        let linked = self.connection_info.lock().unwrap().is_some();
+        let busy = self.is_connecting() || linked || self.is_connected();
+        let last_seen = self.advertising.lock().unwrap().properties.last_seen;
+
+        let expired = should_expire(busy, self.lost.load(Ordering::Relaxed), last_seen, timeout,
+                                    now);
+        if expired {
+            self.lost.store(true, Ordering::Relaxed);
         }
+        expired
     }
 
     pub fn handle_device_message(&self, message: &hci::Message) {
-        match message {
-            &hci::Message::LEAdvertisingReport(ref info) => {
+        match *message {
+            hci::Message::LEConnComplete(ref info) => {
                 assert_eq!(self.address, info.bdaddr, "received message for wrong device");
-                use bluez::protocol::hci::LEAdvertisingData::*;
-
-                let mut properties = self.properties.lock().unwrap();
 
-                properties.discovery_count += 1;
-                properties.address_type = if info.bdaddr_type == 1 {
-                    AddressType::Random
+                debug!("got le conn complete {:?}", info);
+                let result = match info.status {
+                    hci::HCIStatus::Success => {
+                        // connections are always created on the 1M PHY
+                        *self.connection_info.lock().unwrap() = Some(ConnectionInfo {
+                            interval: info.interval,
+                            latency: info.latency,
+                            supervision_timeout: info.supervision_timeout,
+                            tx_phy: Phy::Le1M,
+                            rx_phy: Phy::Le1M,
+                            data_length: DataLength::default(),
+                        });
+                        Ok(info.handle)
+                    }
+                    // this is what the controller reports when a connection attempt is cancelled
+                    hci::HCIStatus::UnknownConnectionID => Err(Error::Cancelled),
+                    ref status => Err(Error::Other(format!("Connection failed: {:?}", status))),
+                };
+                self.connection_results.send(result);
+            }
+            hci::Message::LEReadRemoteUsedFeaturesComplete { ref status, flags, .. } => {
+                debug!("got remote features for {}: {:?}", self.address, flags);
+                let result = if *status == hci::HCIStatus::Success {
+                    *self.remote_features.lock().unwrap() = Some(flags);
+                    Ok(flags)
                 } else {
-                    AddressType::Public
+                    Err(Error::Hci(*status))
                 };
-
-                properties.address = info.bdaddr;
-
-                if info.evt_type == 4 {
-                    // discover event
-                    properties.has_scan_response = true;
+                Peripheral::finish_waiters(&self.feature_waiters, result);
+            }
+            hci::Message::ReadRemoteVersionComplete { ref status, version, .. } => {
+                debug!("got remote version for {}: {:?}", self.address, version);
+                let result = if *status == hci::HCIStatus::Success {
+                    *self.remote_version.lock().unwrap() = Some(version);
+                    Ok(version)
                 } else {
-                    // TODO: reset service data
-                }
-
-                for datum in info.data.iter() {
-                    match datum {
-                        &LocalName(ref name) => {
-                            properties.local_name = Some(name.clone());
-                        }
-                        &TxPowerLevel(ref power) => {
-                            properties.tx_power_level = Some(power.clone());
-                        }
-                        &ManufacturerSpecific(ref data) => {
-                            properties.manufacturer_data = Some(data.clone());
-                        }
-                        _ => {
-                            // skip for now
-                        }
+                    Err(Error::Hci(*status))
+                };
+                Peripheral::finish_waiters(&self.version_waiters, result);
+            }
+            hci::Message::LEConnUpdate(ref info) => {
+                debug!("got le conn update {:?}", info);
+                if info.status == hci::HCIStatus::Success {
+                    if let Some(ref mut connection) = *self.connection_info.lock().unwrap() {
+                        connection.interval = info.interval;
+                        connection.latency = info.latency;
+                        connection.supervision_timeout = info.supervision_timeout;
                     }
                 }
             }
-            &hci::Message::LEConnComplete(ref info) => {
-                assert_eq!(self.address, info.bdaddr, "received message for wrong device");
-
-                debug!("got le conn complete {:?}", info);
-                self.connection_tx.lock().unwrap().send(info.handle.clone()).unwrap();
+            hci::Message::LEDataLengthChange { lengths, .. } => {
+                debug!("got data length change for {}: {:?}", self.address, lengths);
+                if let Some(ref mut connection) = *self.connection_info.lock().unwrap() {
+                    connection.data_length = lengths;
+                }
             }
-            &hci::Message::ACLDataPacket(ref data) => {
-                let handle = data.handle.clone();
+            hci::Message::LEPhyUpdate { ref status, tx_phy, rx_phy, .. } => {
+                debug!("got phy update for {}: {:?} {:?} {:?}", self.address, status, tx_phy,
+                       rx_phy);
+                if *status == hci::HCIStatus::Success {
+                    if let Some(ref mut connection) = *self.connection_info.lock().unwrap() {
+                        connection.tx_phy = tx_phy;
+                        connection.rx_phy = rx_phy;
+                    }
+                }
+            }
+            hci::Message::ACLDataPacket(ref data) => {
+                let handle = data.handle;
                 match self.stream.try_read() {
                     Ok(stream) => {
                         stream.iter().for_each(|stream| {
@@ -177,14 +586,36 @@ impl Peripheral {
                     }
                 }
             },
-            &hci::Message::DisconnectComplete {..} => {
+            hci::Message::ACLDataContinuation { handle, ref data } => {
+                match self.stream.try_read() {
+                    Ok(stream) => {
+                        stream.iter().for_each(|stream| {
+                            if stream.handle == handle {
+                                stream.receive_continuation(data);
+                            }
+                        });
+                    }
+                    Err(_e) => {
+                        debug!("dropping continuation for {} while connecting", self.address);
+                    }
+                }
+            },
+            hci::Message::DisconnectComplete {..} => {
                 // destroy our stream
                 debug!("removing stream for {} due to disconnect", self.address);
                 let mut stream = self.stream.write().unwrap();
                 *stream = None;
+                *self.connection_info.lock().unwrap() = None;
+                *self.remote_features.lock().unwrap() = None;
+                *self.remote_version.lock().unwrap() = None;
+                Peripheral::finish_waiters(&self.feature_waiters, Err(Error::NotConnected));
+                Peripheral::finish_waiters(&self.version_waiters, Err(Error::NotConnected));
                 // TODO clean up our sockets
+
+                // give the device a chance to start advertising again before it expires
+                self.advertising.lock().unwrap().properties.last_seen = Some(Instant::now());
             },
-            msg => {
+            ref msg => {
                 debug!("ignored message {:?}", msg);
             }
         }
@@ -204,15 +635,142 @@ impl Peripheral {
         }
     }
 
-    fn request_raw(&self, data: &mut [u8]) -> Result<Vec<u8>> {
-        Peripheral::wait_until_done(|done: RequestCallback| {
+    fn request_raw(&self, data: &mut [u8], deadline: Deadline) -> Result<Vec<u8>> {
+        Peripheral::wait_until_done(deadline, |done: RequestCallback| {
             // TODO this copy can be avoided
             let mut data = data.to_vec();
             self.request_raw_async(&mut data, Some(done));
         })
     }
 
+    fn read_long_by_handle(&self, handle: u16, handler: Option<RequestCallback>) {
+        let peripheral = self.clone();
+        let mtu = self.mtu() as usize;
+        let handler = Arc::new(Mutex::new(handler));
+        let mut buf = att::read_req(handle);
+        self.request_raw_async(&mut buf, Some(Box::new(move |result: Result<Vec<u8>>| {
+            match result {
+                Ok(ref data) if !data.is_empty() && data[0] == ATT_OP_READ_RESP &&
+                    data.len() >= mtu => {
+                    // the value filled the whole response, so there may be more of it
+                    peripheral.read_blob(handle, data.clone(), mtu, handler.clone());
+                }
+                result => Peripheral::finish(&handler, result),
+            }
+        })));
+    }
+
+    fn read_blob(&self, handle: u16, value: Vec<u8>, mtu: usize,
+                 handler: Arc<Mutex<Option<RequestCallback>>>) {
+        let peripheral = self.clone();
+        // the value starts after the opcode of the original read response
+        let offset = (value.len() - 1) as u16;
+        debug!("reading blob for handle {} at offset {}", handle, offset);
+
+        let mut buf = att::read_blob_req(handle, offset);
+        self.request_raw_async(&mut buf, Some(Box::new(move |result: Result<Vec<u8>>| {
+            match result {
+                Ok(ref data) if !data.is_empty() && data[0] == ATT_OP_READ_BLOB_RESP => {
+                    let mut value = value.clone();
+                    value.extend_from_slice(&data[1..]);
+                    if data.len() >= mtu {
+                        peripheral.read_blob(handle, value, mtu, handler.clone());
+                    } else {
+                        Peripheral::finish(&handler, Ok(value));
+                    }
+                }
+                Err(Error::Att { code: AttError::AttributeNotLong, .. }) |
+                Err(Error::Att { code: AttError::InvalidOffset, .. }) => {
+                    // the previous response happened to end exactly at the end of the value
+                    Peripheral::finish(&handler, Ok(value.clone()));
+                }
+                result => Peripheral::finish(&handler, result),
+            }
+        })));
+    }
+
+    fn finish<T>(handler: &Mutex<Option<Callback<T>>>, result: Result<T>) {
+        if let Some(h) = handler.lock().unwrap().take() {
+            h(result);
+        }
+    }
+
+    fn write_long_by_handle(&self, handle: u16, data: &[u8], reliable: bool,
+                            handler: Option<RequestCallback>) {
+        let handler = Arc::new(Mutex::new(handler));
+        let mtu = self.mtu() as usize;
+        self.prepare_write(handle, Arc::new(data.to_vec()), 0, mtu, reliable, handler);
+    }
+
+    fn prepare_write(&self, handle: u16, data: Arc<Vec<u8>>, offset: usize, mtu: usize,
+                     reliable: bool, handler: Arc<Mutex<Option<RequestCallback>>>) {
+        let end = offset + att::prepare_write_chunk(&data, offset, mtu).len();
+        debug!("preparing write for handle {} of bytes [{}, {})", handle, offset, end);
+
+        let peripheral = self.clone();
+        let mut buf = att::prepare_write_req(handle, offset as u16, &data[offset..end]);
+        self.request_raw_async(&mut buf, Some(Box::new(move |result: Result<Vec<u8>>| {
+            match result {
+                Ok(ref resp) if !resp.is_empty() && resp[0] == ATT_OP_PREP_WRITE_RESP => {
+                    // for reliable writes, the device must echo back exactly what we sent
+                    let matches = !reliable || match att::prepare_write_response(resp) {
+                        Ok((_, ref r)) => r.handle == handle && r.offset as usize == offset &&
+                            r.value[..] == data[offset..end],
+                        Err(_) => false,
+                    };
+
+                    if !matches {
+                        warn!("prepared write for handle {} did not match: {:?}", handle, resp);
+                        peripheral.cancel_prepared_writes(handler.clone(), Err(Error::Other(
+                            "Reliable write failed: value echoed by device did not match"
+                                .to_string())));
+                    } else if end < data.len() {
+                        peripheral.prepare_write(handle, data.clone(), end, mtu, reliable,
+                                                 handler.clone());
+                    } else {
+                        peripheral.execute_prepared_writes(handler.clone());
+                    }
+                }
+                Ok(resp) => {
+                    warn!("Unexpected prepare write response: {:?}", resp);
+                    peripheral.cancel_prepared_writes(handler.clone(), Err(Error::Other(
+                        "Unexpected prepare write response".to_string())));
+                }
+                Err(err @ Error::Att { .. }) => {
+                    // the device rejected the write, so clear out anything it's already queued
+                    peripheral.cancel_prepared_writes(handler.clone(), Err(err));
+                }
+                Err(err) => Peripheral::finish(&handler, Err(err)),
+            }
+        })));
+    }
+
+    fn execute_prepared_writes(&self, handler: Arc<Mutex<Option<RequestCallback>>>) {
+        let mut buf = att::execute_write_req(true);
+        self.request_raw_async(&mut buf, Some(Box::new(move |result: Result<Vec<u8>>| {
+            Peripheral::finish(&handler, result);
+        })));
+    }
+
+    fn cancel_prepared_writes(&self, handler: Arc<Mutex<Option<RequestCallback>>>,
+                              result: Result<Vec<u8>>) {
+        let mut buf = att::execute_write_req(false);
+        self.request_raw_async(&mut buf, Some(Box::new(move |_: Result<Vec<u8>>| {
+            Peripheral::finish(&handler, result.clone());
+        })));
+    }
+
     fn request_by_handle(&self, handle: u16, data: &[u8], handler: Option<RequestCallback>) {
+        if data.len() > self.mtu() as usize - 3 {
+            // too long to fit in a single write request; the long write finishes with an execute
+            // write response, which callers expect to see as a plain write response
+            let handler = handler.map(|h| -> RequestCallback {
+                Box::new(move |result: Result<Vec<u8>>| h(result.map(att::long_write_response)))
+            });
+            self.write_long_by_handle(handle, data, false, handler);
+            return;
+        }
+
         let mut buf = BytesMut::with_capacity(3 + data.len());
         buf.put_u8(ATT_OP_WRITE_REQ);
         buf.put_u16_le(handle);
@@ -220,12 +778,123 @@ impl Peripheral {
         self.request_raw_async(&mut buf, handler);
     }
 
-    fn notify(&self, characteristic: &Characteristic, enable: bool) -> Result<()> {
+    fn discover_services_by_type(&self, group_type: u16, primary: bool,
+                                 deadline: Deadline) -> Result<Vec<Service>> {
+        let mut results: Vec<Service> = vec![];
+        let mut start = 0x0001;
+        loop {
+            debug!("discovering services of type {:04X} from {}", group_type, start);
+
+            let mut buf = att::read_by_group_type_req(start, 0xFFFF, B16(group_type));
+            let data = match self.request_raw(&mut buf, deadline) {
+                Ok(data) => data,
+                // there are no more services to find (or none of this type at all)
+                Err(Error::Att { code: AttError::AttributeNotFound, .. }) |
+                Err(Error::Att { code: AttError::UnsupportedGroupType, .. }) => break,
+                Err(err) => return Err(err),
+            };
+
+            match att::services(&data, primary) {
+                Ok(result) => {
+                    match result.1 {
+                        Ok(services) => {
+                            debug!("Services: {:#?}", services);
+
+                            let next = services.iter().last().map(|s| s.end_handle);
+                            results.extend(services);
+
+                            match next {
+                                Some(end) if end < 0xFFFF => {
+                                    start = end + 1;
+                                    continue;
+                                }
+                                _ => break,
+                            }
+                        }
+                        Err(err) => {
+                            // this generally means we should stop iterating
+                            debug!("got error: {:?}", err);
+                            break;
+                        }
+                    }
+                }
+                Err(err) => {
+                    error!("failed to parse services: {:?}", err);
+                    return Err(Error::Other(format!("failed to parse services response {:?}",
+                                                    err)));
+                }
+            }
+        }
+
+        Ok(results)
+    }
+
+    fn discover_characteristics_until(&self, start: u16, end: u16,
+                                      deadline: Deadline) -> Result<Vec<Characteristic>> {
+        let mut results = vec![];
+        let mut start = start;
+        loop {
+            debug!("discovering chars in range [{}, {}]", start, end);
+
+            let mut buf = att::read_by_type_req(start, end, B16(GATT_CHARAC_UUID));
+            let data = match self.request_raw(&mut buf, deadline) {
+                Ok(data) => data,
+                // there are no more characteristics in the range
+                Err(Error::Att { code: AttError::AttributeNotFound, .. }) => break,
+                Err(err) => return Err(err),
+            };
+
+            match att::characteristics(&data) {
+                Ok(result) => {
+                    match result.1 {
+                        Ok(chars) => {
+                            debug!("Chars: {:#?}", chars);
+
+                            // TODO this copy can be removed
+                            results.extend(chars.clone());
+
+                            if let Some(last) = chars.iter().last() {
+                                if last.start_handle < end - 1 {
+                                    start = last.start_handle + 1;
+                                    continue;
+                                }
+                            }
+                            break;
+                        }
+                        Err(err) => {
+                            // this generally means we should stop iterating
+                            debug!("got error: {:?}", err);
+                            break;
+                        }
+                    }
+                }
+                Err(err) => {
+                    error!("failed to parse chars: {:?}", err);
+                    return Err(Error::Other(format!("failed to parse characteristics response {:?}",
+                                                    err)));
+                }
+            }
+        }
+
+        // fix the end handles (we don't get them directly from device, so we have to infer)
+        for i in 0..results.len() {
+            results.get_mut(i).unwrap().end_handle =
+                results.get(i + 1).map(|c| c.start_handle - 1).unwrap_or(end);
+        }
+
+        // update our cache
+        let mut lock = self.characteristics.lock().unwrap();
+        results.iter().for_each(|c| { lock.insert(c.clone());});
+
+        Ok(results)
+    }
+
+    fn notify(&self, characteristic: &Characteristic, enable: bool, deadline: Deadline) -> Result<()> {
         info!("setting notify for {}/{:?} to {}", self.address, characteristic.uuid, enable);
         let mut buf = att::read_by_type_req(
             characteristic.start_handle, characteristic.end_handle, B16(GATT_CLIENT_CHARAC_CFG_UUID));
 
-        let data = self.request_raw(&mut buf)?;
+        let data = self.request_raw(&mut buf, deadline)?;
 
         match att::notify_response(&data) {
             Ok(resp) => {
@@ -250,7 +919,7 @@ impl Peripheral {
 
                 let mut value_buf = BytesMut::with_capacity(2);
                 value_buf.put_u16_le(value);
-                let data = Peripheral::wait_until_done(|done: RequestCallback| {
+                let data = Peripheral::wait_until_done(deadline, |done: RequestCallback| {
                     self.request_by_handle(resp.1.handle, &*value_buf, Some(done))
                 })?;
 
@@ -269,7 +938,25 @@ impl Peripheral {
         };
     }
 
-    fn wait_until_done<F, T: Clone + Send + 'static>(operation: F) -> Result<T> where F: for<'a> Fn(Callback<T>) {
+    fn deadline(&self) -> Deadline {
+        Deadline::after(self.c_adapter.timeout())
+    }
+
+    fn finish_waiters<T: Clone>(waiters: &Mutex<Vec<Callback<T>>>, result: Result<T>) {
+        let waiters: Vec<Callback<T>> = waiters.lock().unwrap().drain(..).collect();
+        for waiter in waiters {
+            waiter(result.clone());
+        }
+    }
+
+    fn connection_handle(&self) -> Result<u16> {
+        let l = self.stream.read().unwrap();
+        Ok(l.as_ref().ok_or(Error::NotConnected)?.handle)
+    }
+
+    // note that when we give up waiting, the operation itself may still complete later
+    fn wait_until_done<F, T: Clone + Send + 'static>(deadline: Deadline, operation: F) -> Result<T>
+        where F: for<'a> Fn(Callback<T>) {
         let pair = Arc::new((Mutex::new(None), Condvar::new()));
         let pair2 = pair.clone();
         let on_finish = Box::new(move|result: Result<T>| {
@@ -286,14 +973,91 @@ impl Peripheral {
 
         let mut done = lock.lock().unwrap();
         while (*done).is_none() {
-            done = cvar.wait(done).unwrap();
+            let remaining = deadline.remaining()?;
+            done = cvar.wait_timeout(done, remaining).unwrap().0;
         }
 
         // TODO: this copy is avoidable
         (*done).clone().unwrap()
     }
 
-    fn setup_connection(&self, fd: i32) -> Result<u16> {
+    // the largest ATT PDU we're able to receive over the socket
+    fn local_mtu(fd: i32) -> u16 {
+        let mut opts = L2CapOptions::default();
+        let mut len = size_of::<L2CapOptions>() as libc::socklen_t;
+        let result = handle_error(unsafe {
+            libc::getsockopt(fd, SOL_L2CAP, L2CAP_OPTIONS,
+                             &mut opts as *mut L2CapOptions as *mut libc::c_void, &mut len)
+        });
+
+        match result {
+            Ok(_) => opts.imtu.clamp(ATT_DEFAULT_LE_MTU, ATT_MAX_LE_MTU),
+            Err(err) => {
+                warn!("failed to read L2CAP options for socket {}: {}", fd, err);
+                ATT_DEFAULT_LE_MTU
+            }
+        }
+    }
+
+    // creates the acl stream that will communicate with the device
+    fn create_stream(&self, handle: u16, fd: i32) -> ACLStream {
+        // the bearer can't be used again once a transaction times out, so drop the link
+        let adapter = self.c_adapter.clone();
+        let s = ACLStream::new(self.address, handle, fd, Peripheral::local_mtu(fd),
+                               Box::new(move || adapter.drop_link(handle)));
+
+        // replay missed messages
+        let mut queue = self.message_queue.lock().unwrap();
+        while !queue.is_empty() {
+            let msg = queue.pop_back().unwrap();
+            if s.handle == msg.handle {
+                s.receive(&msg);
+            }
+        }
+
+        s
+    }
+
+    // starts connecting to the device with `start` and waits for the connection notice
+    fn setup_connection<F>(&self, start: F, timeout: Duration) -> Result<u16>
+        where F: FnOnce() -> Result<()> {
+        self.connection_results.clear();
+        start()?;
+
+        // wait until we get the connection notice
+        self.connection_results.wait(timeout)
+    }
+
+    /// Opens the ATT channel over a link to the device that the controller has already created,
+    /// such as one made through the white list. Unlike `connect`, this doesn't wait for the
+    /// controller to report the connection, as it already has.
+    pub fn attach(&self, handle: u16, address_type: AddressType) -> Result<()> {
+        let mut stream = self.stream.write().unwrap();
+        if stream.is_none() {
+            *stream = Some(self.open_att_stream(handle, address_type)?);
+        }
+        Ok(())
+    }
+
+    // creates a socket for the ATT channel over the link with the given handle
+    fn open_att_stream(&self, handle: u16, address_type: AddressType) -> Result<ACLStream> {
+        let fd = handle_error(unsafe {
+            libc::socket(libc::AF_BLUETOOTH, libc::SOCK_SEQPACKET, 0)
+        })?;
+        debug!("created socket {} to communicate with device", fd);
+
+        if let Err(e) = self.open_att_socket(fd, address_type.num() + 1) {
+            debug!("Failed to connect ({}), closing socket {}", e, fd);
+            handle_error(unsafe { libc::close(fd) })?;
+            return Err(e);
+        }
+
+        Ok(self.create_stream(handle, fd))
+    }
+
+    // connects the socket to the device's ATT channel; the L2CAP address type is one more than
+    // the HCI address type
+    fn open_att_socket(&self, fd: i32, address_type: u8) -> Result<()> {
         let local_addr = SockaddrL2 {
             l2_family: libc::AF_BLUETOOTH as libc::sa_family_t,
             l2_psm: 0,
@@ -321,7 +1085,7 @@ impl Peripheral {
             l2_psm: 0,
             l2_bdaddr: self.address,
             l2_cid: ATT_CID,
-            l2_bdaddr_type: 1,
+            l2_bdaddr_type: address_type,
         };
 
         // connect to the device
@@ -337,22 +1101,14 @@ impl Peripheral {
             debug!("restarted scanning");
         }
 
-        // wait until we get the connection notice
-        let timeout = Duration::from_secs(20);
-        match self.connection_rx.lock().unwrap().recv_timeout(timeout) {
-            Ok(handle) => {
-                return Ok(handle);
-            }
-            Err(mpsc::RecvTimeoutError::Timeout) => {
-                return Err(Error::TimedOut(timeout.clone()));
-            }
-            err => {
-                // unexpected error
-                err.unwrap();
-                unreachable!();
-            }
-        };
+        Ok(())
     }
+
+    // whether a connection attempt to the device is in progress
+    fn is_connecting(&self) -> bool {
+        self.c_adapter.is_connecting(self.address)
+    }
+
 }
 
 impl ApiPeripheral for Peripheral {
@@ -361,8 +1117,8 @@ impl ApiPeripheral for Peripheral {
     }
 
     fn properties(&self) -> PeripheralProperties {
-        let l = self.properties.lock().unwrap();
-        l.clone()
+        let l = self.advertising.lock().unwrap();
+        l.properties.clone()
     }
 
     fn characteristics(&self) -> BTreeSet<Characteristic> {
@@ -370,12 +1126,25 @@ impl ApiPeripheral for Peripheral {
         l.clone()
     }
 
+    fn services(&self) -> BTreeSet<Service> {
+        let l = self.services.lock().unwrap();
+        l.clone()
+    }
+
     fn is_connected(&self) -> bool {
         let l = self.stream.try_read();
         return l.is_ok() && l.unwrap().is_some();
     }
 
     fn connect(&self) -> Result<()> {
+        self.connect_timeout(Duration::from_secs(DEFAULT_CONNECT_TIMEOUT_SECS))
+    }
+
+    fn connect_timeout(&self, timeout: Duration) -> Result<()> {
+        // this is recorded before taking the lock, so that the device is never seen as neither
+        // connecting nor connected
+        let _attempt = self.c_adapter.begin_connection(self.address)?;
+
         // take lock on stream
         let mut stream = self.stream.write().unwrap();
 
@@ -390,22 +1159,9 @@ impl ApiPeripheral for Peripheral {
         })?;
         debug!("created socket {} to communicate with device", fd);
 
-        match self.setup_connection(fd) {
+        match self.setup_connection(|| self.open_att_socket(fd, 1), timeout) {
             Ok(handle) => {
-                // create the acl stream that will communicate with the device
-                let s = ACLStream::new(self.c_adapter.adapter.clone(),
-                                       self.address, handle, fd);
-
-                // replay missed messages
-                let mut queue = self.message_queue.lock().unwrap();
-                while !queue.is_empty() {
-                    let msg = queue.pop_back().unwrap();
-                    if s.handle == msg.handle {
-                        s.receive(&msg);
-                    }
-                }
-
-                *stream = Some(s);
+                *stream = Some(self.create_stream(handle, fd));
             }
             Err(e) => {
                 // close the socket we opened
@@ -418,7 +1174,41 @@ impl ApiPeripheral for Peripheral {
         Ok(())
     }
 
+    fn connect_with(&self, params: ConnectionParameters) -> Result<()> {
+        // this is recorded before taking the lock, so that the device is never seen as neither
+        // connecting nor connected
+        let _attempt = self.c_adapter.begin_connection(self.address)?;
+
+        // take lock on stream
+        let mut stream = self.stream.write().unwrap();
+
+        if stream.is_some() {
+            // we're already connected, just return
+            return Ok(());
+        }
 
+        let address_type = self.advertising.lock().unwrap().properties.address_type;
+        let peer = Some((self.address, address_type));
+
+        let timeout = self.c_adapter.timeout();
+        let handle = match self.setup_connection(
+            || self.c_adapter.create_connection(peer, &params), timeout) {
+            Ok(handle) => handle,
+            Err(Error::TimedOut(timeout)) => {
+                self.c_adapter.cancel_connection()?;
+                return Err(Error::TimedOut(timeout));
+            }
+            Err(e) => return Err(e),
+        };
+
+        // the controller has already created the link, so this only opens the ATT channel over it
+        *stream = Some(self.open_att_stream(handle, address_type)?);
+        Ok(())
+    }
+
+    fn cancel_connect(&self) -> Result<()> {
+        self.c_adapter.cancel_connection()
+    }
 
     fn disconnect(&self) -> Result<()> {
         let mut l = self.stream.write().unwrap();
@@ -433,42 +1223,261 @@ impl ApiPeripheral for Peripheral {
         let mut data = BytesMut::with_capacity(3);
         data.put_u16_le(handle);
         data.put_u8(HCI_OE_USER_ENDED_CONNECTION);
-        let mut buf = hci::hci_command(DISCONNECT_CMD, &*data);
-        self.c_adapter.write(&mut *buf)?;
+        self.c_adapter.command_with_status(hci::CommandType::Disconnect, &data)?;
 
         *l = None;
         Ok(())
     }
 
+    fn exchange_mtu(&self, mtu: u16) -> Result<u16> {
+        self.exchange_mtu_timeout(mtu, self.c_adapter.timeout())
+    }
+
+    fn exchange_mtu_timeout(&self, mtu: u16, timeout: Duration) -> Result<u16> {
+        let data = Peripheral::wait_until_done(Deadline::after(timeout), |done: RequestCallback| {
+            let l = self.stream.read().unwrap();
+            match l.as_ref() {
+                Some(stream) => stream.exchange_mtu(mtu, Some(done)),
+                None => done(Err(Error::NotConnected)),
+            }
+        })?;
+
+        match att::mtu_response(&data) {
+            Ok((_, response)) => {
+                let l = self.stream.read().unwrap();
+                let stream = l.as_ref().ok_or(Error::NotConnected)?;
+                stream.set_mtu(response.server_rx_mtu.min(mtu));
+                Ok(stream.mtu())
+            }
+            Err(err) => {
+                warn!("Unexpected exchange MTU response: {:?} ({:?})", data, err);
+                Err(Error::Other("Failed to exchange MTU".to_string()))
+            }
+        }
+    }
+
+    fn read_rssi(&self) -> Result<i8> {
+        let handle = self.connection_handle()?;
+
+        let mut data = BytesMut::with_capacity(2);
+        data.put_u16_le(handle);
+        // the command queue has already turned a failure status into an error
+        match self.c_adapter.command_sync(hci::CommandType::ReadRSSI, &data)? {
+            hci::CommandComplete::ReadRSSI { rssi, .. } => Ok(rssi),
+            complete => Err(ConnectedAdapter::unexpected(complete)),
+        }
+    }
+
+    fn set_phy(&self, tx: PhyFlags, rx: PhyFlags, options: CodedPhyOptions) -> Result<()> {
+        let handle = self.connection_handle()?;
+
+        if let Some(features) = self.remote_features() {
+            let mut supported = PhyFlags::LE_1M;
+            supported.set(PhyFlags::LE_2M, features.contains(LEFeatureFlags::LE_2M_PHY));
+            supported.set(PhyFlags::LE_CODED, features.contains(LEFeatureFlags::LE_CODED_PHY));
+            for phys in &[tx, rx] {
+                if !phys.is_empty() && !phys.intersects(supported) {
+                    return Err(Error::NotSupported(
+                        format!("{} does not support {:?}", self.address, phys)));
+                }
+            }
+        }
+
+        self.c_adapter.set_phy(handle, tx, rx, options)
+    }
+
+    fn set_data_length(&self, tx_octets: u16, tx_time: u16) -> Result<()> {
+        if !(27..=251).contains(&tx_octets) || !(328..=17040).contains(&tx_time) {
+            return Err(Error::Other(format!("invalid data length {} bytes, {} us",
+                                            tx_octets, tx_time)));
+        }
+
+        let handle = self.connection_handle()?;
+
+        if let Some(features) = self.remote_features() {
+            if !features.contains(LEFeatureFlags::DATA_PACKET_LENGTH_EXTENSION) {
+                return Err(Error::NotSupported(
+                    format!("{} does not support data length extension", self.address)));
+            }
+        }
+
+        let mut data = BytesMut::with_capacity(6);
+        data.put_u16_le(handle);
+        data.put_u16_le(tx_octets);
+        data.put_u16_le(tx_time);
+        self.c_adapter.command_with_status(hci::CommandType::LESetDataLength, &data)
+    }
+
+    fn read_phy(&self) -> Result<(Phy, Phy)> {
+        let handle = self.connection_handle()?;
+
+        let mut data = BytesMut::with_capacity(2);
+        data.put_u16_le(handle);
+        match self.c_adapter.command_sync(hci::CommandType::LEReadPHY, &data)? {
+            hci::CommandComplete::LEReadPHY { status: 0, tx_phy, rx_phy, .. } => {
+                match (Phy::from_u8(tx_phy), Phy::from_u8(rx_phy)) {
+                    (Some(tx), Some(rx)) => Ok((tx, rx)),
+                    _ => Err(Error::Other(format!("Unknown PHYs {} and {}", tx_phy, rx_phy))),
+                }
+            }
+            complete => {
+                warn!("Unexpected read PHY response: {:?}", complete);
+                Err(Error::Other("Failed to read PHY".to_string()))
+            }
+        }
+    }
+
+    fn remote_features(&self) -> Option<LEFeatureFlags> {
+        *self.remote_features.lock().unwrap()
+    }
+
+    fn read_remote_features(&self) -> Result<LEFeatureFlags> {
+        let handle = self.connection_handle()?;
+        Peripheral::wait_until_done(self.deadline(), |done| {
+            self.feature_waiters.lock().unwrap().push(done);
+            let waiters = self.feature_waiters.clone();
+            self.c_adapter.read_remote_features(handle, Some(Box::new(move |result| {
+                if let Err(e) = result {
+                    Peripheral::finish_waiters(&waiters, Err(e));
+                }
+            })));
+        })
+    }
+
+    fn remote_version(&self) -> Option<RemoteVersion> {
+        *self.remote_version.lock().unwrap()
+    }
+
+    fn read_remote_version(&self) -> Result<RemoteVersion> {
+        let handle = self.connection_handle()?;
+        Peripheral::wait_until_done(self.deadline(), |done| {
+            self.version_waiters.lock().unwrap().push(done);
+            let waiters = self.version_waiters.clone();
+            self.c_adapter.read_remote_version(handle, Some(Box::new(move |result| {
+                if let Err(e) = result {
+                    Peripheral::finish_waiters(&waiters, Err(e));
+                }
+            })));
+        })
+    }
+
+    fn connection_parameters(&self) -> Option<ConnectionInfo> {
+        *self.connection_info.lock().unwrap()
+    }
+
+    fn update_connection_parameters(&self, min_interval: u16, max_interval: u16, latency: u16,
+                                    timeout: u16) -> Result<()> {
+        let handle = self.connection_handle()?;
+
+        self.c_adapter.update_connection(handle, min_interval, max_interval, latency, timeout)
+    }
+
+    fn mtu(&self) -> u16 {
+        let l = self.stream.read().unwrap();
+        l.as_ref().map(|s| s.mtu()).unwrap_or(ATT_DEFAULT_LE_MTU)
+    }
+
+    fn discover_services(&self) -> Result<Vec<Service>> {
+        self.discover_services_timeout(self.c_adapter.timeout())
+    }
+
+    fn discover_services_timeout(&self, timeout: Duration) -> Result<Vec<Service>> {
+        let deadline = Deadline::after(timeout);
+        let mut services = self.discover_services_by_type(GATT_PRIM_SVC_UUID, true, deadline)?;
+        services.extend(self.discover_services_by_type(GATT_SND_SVC_UUID, false, deadline)?);
+
+        for service in services.iter_mut() {
+            let chars = self.discover_characteristics_until(
+                service.start_handle, service.end_handle, deadline)?;
+            service.characteristics = chars.into_iter().collect();
+        }
+
+        // update our cache
+        let mut lock = self.services.lock().unwrap();
+        lock.clear();
+        services.iter().for_each(|s| { lock.insert(s.clone());});
+
+        Ok(services)
+    }
+
     fn discover_characteristics(&self) -> Result<Vec<Characteristic>> {
-        self.discover_characteristics_in_range(0x0001, 0xFFFF)
+        self.discover_characteristics_timeout(self.c_adapter.timeout())
+    }
+
+    fn discover_characteristics_timeout(&self, timeout: Duration) -> Result<Vec<Characteristic>> {
+        self.discover_characteristics_until(0x0001, 0xFFFF, Deadline::after(timeout))
     }
 
     fn discover_characteristics_in_range(&self, start: u16, end: u16) -> Result<Vec<Characteristic>> {
+        self.discover_characteristics_in_range_timeout(start, end, self.c_adapter.timeout())
+    }
+
+    fn discover_characteristics_in_range_timeout(&self, start: u16, end: u16,
+                                                 timeout: Duration) -> Result<Vec<Characteristic>> {
+        self.discover_characteristics_until(start, end, Deadline::after(timeout))
+    }
+
+    fn discover_descriptors(&self, characteristic: &Characteristic) -> Result<Vec<Descriptor>> {
+        self.discover_descriptors_timeout(characteristic, self.c_adapter.timeout())
+    }
+
+    fn discover_descriptors_timeout(&self, characteristic: &Characteristic,
+                                    timeout: Duration) -> Result<Vec<Descriptor>> {
         let mut results = vec![];
-        let mut start = start;
+        // the last characteristic of a service runs to the end of the service, which may be
+        // reported as 0xFFFF; never look past the service that owns the characteristic
+        let service_end = self.services.lock().unwrap().iter()
+            .find(|s| s.start_handle <= characteristic.start_handle &&
+                characteristic.start_handle <= s.end_handle)
+            .map(|s| s.end_handle)
+            .unwrap_or(0xFFFF);
+        let end = cmp::min(characteristic.end_handle, service_end);
+        if characteristic.value_handle >= end {
+            // there's no room for any descriptors after the value
+            return Ok(results);
+        }
+
+        let deadline = Deadline::after(timeout);
+        let mut start = characteristic.value_handle + 1;
         loop {
-            debug!("discovering chars in range [{}, {}]", start, end);
+            debug!("discovering descriptors in range [{}, {}]", start, end);
 
-            let mut buf = att::read_by_type_req(start, end, B16(GATT_CHARAC_UUID));
-            let data = self.request_raw(&mut buf)?;
+            let mut buf = att::find_information_req(start, end);
+            let data = match self.request_raw(&mut buf, deadline) {
+                Ok(data) => data,
+                // there are no more descriptors in the range
+                Err(Error::Att { code: AttError::AttributeNotFound, .. }) => break,
+                Err(err) => return Err(err),
+            };
 
-            match att::characteristics(&data) {
+            match att::descriptors(&data) {
                 Ok(result) => {
                     match result.1 {
-                        Ok(chars) => {
-                            debug!("Chars: {:#?}", chars);
-
-                            // TODO this copy can be removed
-                            results.extend(chars.clone());
+                        Ok(descriptors) => {
+                            debug!("Descriptors: {:#?}", descriptors);
+
+                            let next = descriptors.iter().last().map(|d| d.handle);
+                            // a service or characteristic declaration marks the end of this
+                            // characteristic's descriptors
+                            let declaration = descriptors.iter().position(|d| match d.uuid {
+                                UUID::B16(u) => u == GATT_PRIM_SVC_UUID ||
+                                    u == GATT_SND_SVC_UUID || u == GATT_INCLUDE_UUID ||
+                                    u == GATT_CHARAC_UUID,
+                                _ => false,
+                            });
+                            if let Some(idx) = declaration {
+                                results.extend(descriptors.into_iter().take(idx));
+                                break;
+                            }
+                            results.extend(descriptors);
 
-                            if let Some(ref last) = chars.iter().last() {
-                                if last.start_handle < end - 1 {
-                                    start = last.start_handle + 1;
+                            match next {
+                                Some(handle) if handle < end => {
+                                    start = handle + 1;
                                     continue;
                                 }
+                                _ => break,
                             }
-                            break;
                         }
                         Err(err) => {
                             // this generally means we should stop iterating
@@ -478,29 +1487,53 @@ impl ApiPeripheral for Peripheral {
                     }
                 }
                 Err(err) => {
-                    error!("failed to parse chars: {:?}", err);
-                    return Err(Error::Other(format!("failed to parse characteristics response {:?}",
+                    error!("failed to parse descriptors: {:?}", err);
+                    return Err(Error::Other(format!("failed to parse descriptors response {:?}",
                                                     err)));
                 }
             }
         }
 
-        // fix the end handles (we don't get them directly from device, so we have to infer)
-        for i in 0..results.len() {
-            (*results.get_mut(i).unwrap()).end_handle =
-                results.get(i + 1).map(|c| c.end_handle).unwrap_or(end);
-        }
+        Ok(results)
+    }
 
-        // update our cache
-        let mut lock = self.characteristics.lock().unwrap();
-        results.iter().for_each(|c| { lock.insert(c.clone());});
+    fn read_descriptor(&self, descriptor: &Descriptor) -> Result<Vec<u8>> {
+        self.read_descriptor_timeout(descriptor, self.c_adapter.timeout())
+    }
 
-        Ok(results)
+    fn read_descriptor_timeout(&self, descriptor: &Descriptor,
+                               timeout: Duration) -> Result<Vec<u8>> {
+        Peripheral::wait_until_done(Deadline::after(timeout), |done: RequestCallback| {
+            self.read_long_by_handle(descriptor.handle, Some(done));
+        })
+    }
+
+    fn write_descriptor(&self, descriptor: &Descriptor, data: &[u8]) -> Result<()> {
+        self.write_descriptor_timeout(descriptor, data, self.c_adapter.timeout())
+    }
+
+    fn write_descriptor_timeout(&self, descriptor: &Descriptor, data: &[u8],
+                                timeout: Duration) -> Result<()> {
+        let data = Peripheral::wait_until_done(Deadline::after(timeout), |done: RequestCallback| {
+            self.request_by_handle(descriptor.handle, data, Some(done))
+        })?;
+
+        if !data.is_empty() && data[0] == ATT_OP_WRITE_RESP {
+            Ok(())
+        } else {
+            warn!("Unexpected descriptor write response: {:?}", data);
+            Err(Error::Other("Failed to write descriptor".to_string()))
+        }
     }
 
     fn command_async(&self, characteristic: &Characteristic, data: &[u8], handler: Option<CommandCallback>) {
         let l = self.stream.read().unwrap();
         match l.as_ref() {
+            Some(stream) if data.len() > stream.mtu() as usize - 3 => {
+                handler.iter().for_each(|h| h(Err(Error::NotSupported(format!(
+                    "a command of {} bytes does not fit in the ATT MTU of {}",
+                    data.len(), stream.mtu())))));
+            }
             Some(stream) => {
                 let mut buf = BytesMut::with_capacity(3 + data.len());
                 buf.put_u8(ATT_OP_WRITE_CMD);
@@ -516,7 +1549,12 @@ impl ApiPeripheral for Peripheral {
     }
 
     fn command(&self, characteristic: &Characteristic, data: &[u8]) -> Result<()> {
-        Peripheral::wait_until_done(|done: CommandCallback| {
+        self.command_timeout(characteristic, data, self.c_adapter.timeout())
+    }
+
+    fn command_timeout(&self, characteristic: &Characteristic, data: &[u8],
+                       timeout: Duration) -> Result<()> {
+        Peripheral::wait_until_done(Deadline::after(timeout), |done: CommandCallback| {
             self.command_async(characteristic, data, Some(done));
         })
     }
@@ -526,19 +1564,48 @@ impl ApiPeripheral for Peripheral {
     }
 
     fn request(&self, characteristic: &Characteristic, data: &[u8]) -> Result<Vec<u8>> {
-        Peripheral::wait_until_done(|done: RequestCallback| {
+        self.request_timeout(characteristic, data, self.c_adapter.timeout())
+    }
+
+    fn request_timeout(&self, characteristic: &Characteristic, data: &[u8],
+                       timeout: Duration) -> Result<Vec<u8>> {
+        Peripheral::wait_until_done(Deadline::after(timeout), |done: RequestCallback| {
             self.request_async(characteristic, data, Some(done));
         })
     }
 
+    fn request_reliable_async(&self, characteristic: &Characteristic, data: &[u8],
+                              handler: Option<RequestCallback>) {
+        self.write_long_by_handle(characteristic.value_handle, data, true, handler);
+    }
+
+    fn request_reliable(&self, characteristic: &Characteristic, data: &[u8]) -> Result<Vec<u8>> {
+        self.request_reliable_timeout(characteristic, data, self.c_adapter.timeout())
+    }
+
+    fn request_reliable_timeout(&self, characteristic: &Characteristic, data: &[u8],
+                                timeout: Duration) -> Result<Vec<u8>> {
+        Peripheral::wait_until_done(Deadline::after(timeout), |done: RequestCallback| {
+            self.request_reliable_async(characteristic, data, Some(done));
+        })
+    }
+
     fn read_async(&self, characteristic: &Characteristic, handler: Option<RequestCallback>) {
         let mut buf = att::read_req(characteristic.value_handle);
         self.request_raw_async(&mut buf, handler);
     }
 
+    fn read_long_async(&self, characteristic: &Characteristic, handler: Option<RequestCallback>) {
+        self.read_long_by_handle(characteristic.value_handle, handler);
+    }
+
     fn read(&self, characteristic: &Characteristic) -> Result<Vec<u8>> {
-        Peripheral::wait_until_done(|done: RequestCallback| {
-            self.read_async(characteristic, Some(done));
+        self.read_timeout(characteristic, self.c_adapter.timeout())
+    }
+
+    fn read_timeout(&self, characteristic: &Characteristic, timeout: Duration) -> Result<Vec<u8>> {
+        Peripheral::wait_until_done(Deadline::after(timeout), |done: RequestCallback| {
+            self.read_long_async(characteristic, Some(done));
         })
     }
 
@@ -549,18 +1616,36 @@ impl ApiPeripheral for Peripheral {
     }
 
     fn read_by_type(&self, characteristic: &Characteristic, uuid: UUID) -> Result<Vec<u8>> {
-        Peripheral::wait_until_done(|done: RequestCallback| {
+        self.read_by_type_timeout(characteristic, uuid, self.c_adapter.timeout())
+    }
+
+    fn read_by_type_timeout(&self, characteristic: &Characteristic, uuid: UUID,
+                            timeout: Duration) -> Result<Vec<u8>> {
+        Peripheral::wait_until_done(Deadline::after(timeout), |done: RequestCallback| {
             self.read_by_type_async(characteristic, uuid, Some(done));
         })
     }
 
 
     fn subscribe(&self, characteristic: &Characteristic) -> Result<()> {
-        self.notify(characteristic, true)
+        self.subscribe_timeout(characteristic, self.c_adapter.timeout())
+    }
+
+    fn subscribe_timeout(&self, characteristic: &Characteristic, timeout: Duration) -> Result<()> {
+        self.notify(characteristic, true, Deadline::after(timeout))
     }
 
     fn unsubscribe(&self, characteristic: &Characteristic) -> Result<()> {
-        self.notify(characteristic, false)
+        self.unsubscribe_timeout(characteristic, self.c_adapter.timeout())
+    }
+
+    fn unsubscribe_timeout(&self, characteristic: &Characteristic, timeout: Duration) -> Result<()> {
+        self.notify(characteristic, false, Deadline::after(timeout))
+    }
+
+    fn cancel_pending(&self) {
+        let l = self.stream.read().unwrap();
+        l.iter().for_each(|stream| stream.cancel(Error::Cancelled));
     }
 
     fn on_notification(&self, handler: NotificationHandler) {
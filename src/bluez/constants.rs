@@ -3,7 +3,9 @@
 pub const HCI_COMMAND_PKT: u8 = 0x01;
 
 // hci.h
+pub const HCI_DATA_DIR: i32 = 1;
 pub const HCI_FILTER: i32 = 2;
+pub const HCI_CMSG_DIR: i32 = 0x0001;
 pub const HCI_EVENT_PKT: i32 = 0x04;
 pub const HCI_ACLDATA_PKT: i32 = 0x02;
 pub const HCI_LE_META_EVENT: i32 = 0x3E;
@@ -21,22 +23,71 @@ pub const HCI_OE_USER_ENDED_CONNECTION: u8 = 0x13;
 
 // bluetooth.h
 pub const SOL_HCI: i32 = 0;
+pub const SOL_L2CAP: i32 = 6;
+
+// l2cap.h
+pub const L2CAP_OPTIONS: i32 = 0x01;
+
+pub const L2CAP_CONN_PARAM_UPDATE_REQ: u8 = 0x12;
 
 pub const ATT_CID: u16 = 4;
+pub const LE_SIGNALING_CID: u16 = 5;
 pub const ATT_OP_ERROR_RESP: u8 = 0x01;
 pub const ATT_OP_EXCHANGE_MTU_REQ: u8 = 0x02;
 pub const ATT_OP_EXCHANGE_MTU_RESP: u8 = 0x03;
+pub const ATT_OP_FIND_INFO_REQ: u8 = 0x04;
+pub const ATT_OP_FIND_INFO_RESP: u8 = 0x05;
+pub const ATT_OP_FIND_BY_TYPE_REQ: u8 = 0x06;
+pub const ATT_OP_FIND_BY_TYPE_RESP: u8 = 0x07;
 pub const ATT_OP_READ_BY_TYPE_REQ: u8 = 0x08;
 pub const ATT_OP_READ_BY_TYPE_RESP: u8 = 0x09;
 pub const ATT_OP_READ_REQ: u8 = 0x0a;
 pub const ATT_OP_READ_RESP: u8 = 0x0b;
+pub const ATT_OP_READ_BLOB_REQ: u8 = 0x0c;
+pub const ATT_OP_READ_BLOB_RESP: u8 = 0x0d;
+pub const ATT_OP_READ_MULTI_REQ: u8 = 0x0e;
+pub const ATT_OP_READ_MULTI_RESP: u8 = 0x0f;
 pub const ATT_OP_READ_BY_GROUP_REQ: u8 = 0x10;
 pub const ATT_OP_READ_BY_GROUP_RESP: u8 = 0x11;
 pub const ATT_OP_WRITE_REQ: u8 = 0x12;
 pub const ATT_OP_WRITE_RESP: u8 = 0x13;
+pub const ATT_OP_PREP_WRITE_REQ: u8 = 0x16;
+pub const ATT_OP_PREP_WRITE_RESP: u8 = 0x17;
+pub const ATT_OP_EXEC_WRITE_REQ: u8 = 0x18;
+pub const ATT_OP_EXEC_WRITE_RESP: u8 = 0x19;
 pub const ATT_OP_VALUE_NOTIFICATION: u8 = 0x1b;
+pub const ATT_OP_VALUE_INDICATION: u8 = 0x1d;
+pub const ATT_OP_VALUE_CONFIRMATION: u8 = 0x1e;
 pub const ATT_OP_WRITE_CMD: u8 = 0x52;
 
+pub const ATT_EXEC_WRITE_CANCEL: u8 = 0x00;
+pub const ATT_EXEC_WRITE_COMMIT: u8 = 0x01;
+
+pub const ATT_DEFAULT_LE_MTU: u16 = 23;
+pub const ATT_MAX_LE_MTU: u16 = 517;
+
+// a request that hasn't been answered within this time fails, and the bearer must be closed
+pub const ATT_TRANSACTION_TIMEOUT_SECS: u64 = 30;
+
+// how long synchronous operations wait for the device unless told otherwise
+pub const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+// how long `connect` waits for the connection to be established
+pub const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 20;
+
+// how often we check for devices that have stopped advertising
+pub const EXPIRY_CHECK_INTERVAL_MS: u64 = 1000;
+
+// how long we remember a device that hasn't matched the scan filter since it last advertised
+pub const UNMATCHED_DEVICE_TIMEOUT_SECS: u64 = 10;
+
+// 00000000-0000-1000-8000-00805F9B34FB, in the little-endian order used on the air
+pub const BLUETOOTH_BASE_UUID: [u8; 16] = [0xFB, 0x34, 0x9B, 0x5F, 0x80, 0x00, 0x00, 0x80,
+    0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+pub const GATT_PRIM_SVC_UUID: u16 = 0x2800;
+pub const GATT_SND_SVC_UUID: u16 = 0x2801;
+pub const GATT_INCLUDE_UUID: u16 = 0x2802;
 pub const GATT_CHARAC_UUID: u16 = 0x2803;
 
 pub const GATT_CLIENT_CHARAC_CFG_UUID: u16 = 0x2902;
@@ -44,6 +95,7 @@ pub const GATT_SERVER_CHARAC_CFG_UUID: u16 = 0x2903;
 
 pub const EVT_DISCONN_COMPLETE: u8 = 0x05;
 pub const EVT_ENCRYPT_CHANGE: u8 = 0x08;
+pub const EVT_READ_REMOTE_VERSION_COMPLETE: u8 = 0x0c;
 pub const EVT_CMD_COMPLETE: u8 = 0x0e;
 pub const EVT_CMD_STATUS: u8 = 0x0f;
 pub const EVT_LE_META_EVENT: u8 = 0x3e;
@@ -63,6 +115,7 @@ pub const OCF_DISCONNECT: u16 = 0x0006;
 
 pub const OGF_INFO_PARAM: u8 = 0x04;
 pub const OCF_READ_LOCAL_VERSION: u16 = 0x0001;
+pub const OCF_READ_BUFFER_SIZE: u16 = 0x0005;
 pub const OCF_READ_BD_ADDR: u16 = 0x0009;
 
 pub const OGF_STATUS_PARAM: u8 = 0x05;
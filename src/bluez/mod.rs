@@ -4,6 +4,8 @@ mod protocol;
 mod util;
 mod constants;
 
+pub use self::protocol::hci::HCIStatus;
+
 
 mod ioctl {
     use super::adapter;
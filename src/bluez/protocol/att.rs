@@ -1,10 +1,12 @@
-use nom::{le_u8, le_u16, IResult};
+use nom::{le_u8, le_u16, IResult, Err, ErrorKind};
 
-use ::api::{Characteristic, UUID, CharPropFlags, ValueNotification};
+use ::api::{Characteristic, Descriptor, Service, UUID, CharPropFlags, ValueNotification};
+use std::collections::BTreeSet;
 
 use bluez::constants::*;
 use bluez::protocol::*;
 use bytes::{BytesMut, BufMut};
+use ::{Error, AttError};
 
 #[cfg(test)]
 mod tests {
@@ -41,6 +43,167 @@ mod tests {
         ))))
     }
 
+    #[test]
+    fn test_services() {
+        let buf = [17, 6, 1, 0, 7, 0, 0, 24, 8, 0, 11, 0, 1, 24];
+        assert_eq!(services(&buf, true), Ok((
+            &[][..],
+            Ok(vec![
+                Service {
+                    start_handle: 1,
+                    end_handle: 7,
+                    uuid: UUID::B16(0x1800),
+                    primary: true,
+                    characteristics: BTreeSet::new(),
+                },
+                Service {
+                    start_handle: 8,
+                    end_handle: 11,
+                    uuid: UUID::B16(0x1801),
+                    primary: true,
+                    characteristics: BTreeSet::new(),
+                },
+            ]
+        ))));
+
+        let buf = [17, 20, 12, 0, 255, 255, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+        assert_eq!(services(&buf, false), Ok((
+            &[][..],
+            Ok(vec![
+                Service {
+                    start_handle: 12,
+                    end_handle: 0xFFFF,
+                    uuid: UUID::B128([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]),
+                    primary: false,
+                    characteristics: BTreeSet::new(),
+                },
+            ]
+        ))));
+    }
+
+    #[test]
+    fn test_services_zero_record_length() {
+        let buf = [17, 0, 1, 0, 7, 0, 0, 24];
+        assert!(services(&buf, true).is_err());
+
+        let buf = [9, 0, 2, 0, 2, 3, 0, 0, 42];
+        assert!(characteristics(&buf).is_err());
+    }
+
+    #[test]
+    fn test_long_write() {
+        let mtu = ATT_DEFAULT_LE_MTU as usize;
+        let value: Vec<u8> = (0..50).collect();
+        assert!(value.len() > mtu - 3);
+
+        let mut offset = 0;
+        let mut written = vec![];
+        while offset < value.len() {
+            let chunk = prepare_write_chunk(&value, offset, mtu);
+            let req = prepare_write_req(0x0010, offset as u16, chunk);
+            assert!(req.len() <= mtu);
+            written.extend_from_slice(chunk);
+            offset += chunk.len();
+        }
+        assert_eq!(value, written);
+
+        assert_eq!(vec![ATT_OP_WRITE_RESP], long_write_response(vec![ATT_OP_EXEC_WRITE_RESP]));
+        assert_eq!(vec![ATT_OP_ERROR_RESP, 0x18, 0x10, 0x00, 0x0d],
+                   long_write_response(vec![ATT_OP_ERROR_RESP, 0x18, 0x10, 0x00, 0x0d]));
+    }
+
+    #[test]
+    fn test_response_opcode() {
+        assert_eq!(Some(ATT_OP_READ_RESP), response_opcode(ATT_OP_READ_REQ));
+        assert_eq!(Some(ATT_OP_EXEC_WRITE_RESP), response_opcode(ATT_OP_EXEC_WRITE_REQ));
+        assert_eq!(None, response_opcode(ATT_OP_WRITE_CMD));
+        assert_eq!(None, response_opcode(0xFF));
+    }
+
+    #[test]
+    fn test_read_by_group_type_req() {
+        let expected: Vec<u8> = vec![0x10, 0x01, 0x00, 0xFF, 0xFF, 0x00, 0x28];
+        assert_eq!(expected, read_by_group_type_req(0x0001, 0xFFFF, UUID::B16(0x2800)));
+    }
+
+    #[test]
+    fn test_descriptors() {
+        let buf = [5, 1, 14, 0, 2, 41, 15, 0, 1, 41];
+        assert_eq!(descriptors(&buf), Ok((
+            &[][..],
+            Ok(vec![
+                Descriptor { handle: 14, uuid: UUID::B16(0x2902) },
+                Descriptor { handle: 15, uuid: UUID::B16(0x2901) },
+            ])
+        )));
+
+        let buf = [5, 2, 16, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+        assert_eq!(descriptors(&buf), Ok((
+            &[][..],
+            Ok(vec![
+                Descriptor {
+                    handle: 16,
+                    uuid: UUID::B128([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]),
+                },
+            ])
+        )));
+
+        let buf = [1, 4, 16, 0, 10];
+        assert_eq!(descriptors(&buf), Ok((
+            &[][..],
+            Err(ErrorResponse {
+                request_opcode: 0x04,
+                handle: 0x10,
+                error_code: 0x0a,
+            })
+        )));
+    }
+
+    #[test]
+    fn test_find_information_req() {
+        let expected: Vec<u8> = vec![0x04, 0x0E, 0x00, 0x10, 0x00];
+        assert_eq!(expected, find_information_req(0x000E, 0x0010));
+    }
+
+    #[test]
+    fn test_read_blob_req() {
+        let expected: Vec<u8> = vec![0x0C, 0x25, 0x00, 0x16, 0x00];
+        assert_eq!(expected, read_blob_req(0x0025, 22));
+    }
+
+    #[test]
+    fn test_prepare_write() {
+        let expected: Vec<u8> = vec![0x16, 0x25, 0x00, 0x12, 0x00, 1, 2, 3];
+        assert_eq!(expected, prepare_write_req(0x0025, 18, &[1, 2, 3]));
+
+        let buf = [0x17, 0x25, 0x00, 0x12, 0x00, 1, 2, 3];
+        assert_eq!(prepare_write_response(&buf), Ok((
+            &[][..],
+            PrepareWriteResponse {
+                handle: 0x25,
+                offset: 18,
+                value: vec![1, 2, 3],
+            }
+        )));
+    }
+
+    #[test]
+    fn test_execute_write_req() {
+        assert_eq!(vec![0x18, 0x01], execute_write_req(true));
+        assert_eq!(vec![0x18, 0x00], execute_write_req(false));
+    }
+
+    #[test]
+    fn test_exchange_mtu() {
+        assert_eq!(vec![0x02, 0xF7, 0x00], exchange_mtu_req(247));
+
+        let buf = [0x03, 0x9E, 0x00];
+        assert_eq!(mtu_response(&buf), Ok((
+            &[][..],
+            ExchangeMTUResponse { server_rx_mtu: 158 }
+        )));
+    }
+
     #[test]
     fn test_value_notification() {
         let buf = [27, 46, 0, 165, 17, 5, 0, 0, 130, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
@@ -49,6 +212,20 @@ mod tests {
             ValueNotification {
                 handle: 46,
                 value: vec![165, 17, 5, 0, 0, 130, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+                indication: false,
+            })
+        ));
+    }
+
+    #[test]
+    fn test_value_indication() {
+        let buf = [29, 46, 0, 165, 17];
+        assert_eq!(value_notification(&buf), Ok((
+            &[][..],
+            ValueNotification {
+                handle: 46,
+                value: vec![165, 17],
+                indication: true,
             })
         ));
     }
@@ -66,6 +243,43 @@ mod tests {
         )))
     }
 
+    #[test]
+    fn test_error_into() {
+        let err: Error = ErrorResponse {
+            request_opcode: 0x0a,
+            handle: 0x25,
+            error_code: 0x05,
+        }.into();
+
+        match err {
+            Error::Att { request_opcode, handle, code } => {
+                assert_eq!(0x0a, request_opcode);
+                assert_eq!(0x25, handle);
+                assert_eq!(AttError::InsufficientAuthentication, code);
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        assert_eq!(AttError::Application(0x80), AttError::from_u8(0x80));
+        assert_eq!(AttError::Reserved(0x20), AttError::from_u8(0x20));
+        assert_eq!(0x0F, AttError::from_u8(0x0F).num());
+    }
+
+    #[test]
+    fn test_check_response() {
+        assert_eq!(Ok(vec![0x0b, 1, 2]), check_response(vec![0x0b, 1, 2]).map_err(|_| ()));
+        match check_response(vec![1, 18, 32, 0, 3]) {
+            Err(Error::Att { code: AttError::WriteNotPermitted, .. }) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_error_resp() {
+        let expected: Vec<u8> = vec![0x01, 0x12, 0x20, 0x00, 0x06];
+        assert_eq!(expected, error_resp(0x12, 0x0020, AttError::RequestNotSupported));
+    }
+
     #[test]
     fn test_read_req() {
         let expected: Vec<u8> = vec![0x0A, 0x25, 0x00];
@@ -105,11 +319,35 @@ named!(pub mtu_request<&[u8], ExchangeMTURequest>,
       )
     ));
 
+#[derive(Debug, PartialEq)]
+pub struct ExchangeMTUResponse {
+    pub server_rx_mtu: u16,
+}
+
+named!(pub mtu_response<&[u8], ExchangeMTUResponse>,
+    do_parse!(
+      _op: tag!(&[ATT_OP_EXCHANGE_MTU_RESP]) >>
+      server_rx_mtu: le_u16 >>
+      (
+        ExchangeMTUResponse { server_rx_mtu }
+      )
+    ));
+
 #[derive(Debug, PartialEq)]
 pub struct ErrorResponse {
-    request_opcode: u8,
-    handle: u16,
-    error_code: u8,
+    pub request_opcode: u8,
+    pub handle: u16,
+    pub error_code: u8,
+}
+
+impl From<ErrorResponse> for Error {
+    fn from(e: ErrorResponse) -> Self {
+        Error::Att {
+            request_opcode: e.request_opcode,
+            handle: e.handle,
+            code: AttError::from_u8(e.error_code),
+        }
+    }
 }
 
 named!(pub error_response<&[u8], ErrorResponse>,
@@ -122,13 +360,41 @@ named!(pub error_response<&[u8], ErrorResponse>,
         )
 ));
 
+#[derive(Debug, PartialEq)]
+pub struct PrepareWriteResponse {
+    pub handle: u16,
+    pub offset: u16,
+    pub value: Vec<u8>,
+}
+
+named!(pub prepare_write_response<&[u8], PrepareWriteResponse>,
+    do_parse!(
+        _op: tag!(&[ATT_OP_PREP_WRITE_RESP]) >>
+        handle: le_u16 >>
+        offset: le_u16 >>
+        value: many0!(complete!(le_u8)) >>
+        (
+           PrepareWriteResponse { handle, offset, value }
+        )
+));
+
+/// Turns the execute write response that completes a long write into the write response a
+/// single write request would have produced, passing any other PDU through.
+pub fn long_write_response(data: Vec<u8>) -> Vec<u8> {
+    if data.first() == Some(&ATT_OP_EXEC_WRITE_RESP) {
+        vec![ATT_OP_WRITE_RESP]
+    } else {
+        data
+    }
+}
+
 named!(pub value_notification<&[u8], ValueNotification>,
     do_parse!(
-        _op: tag!(&[ATT_OP_VALUE_NOTIFICATION]) >>
+        op: alt!(tag!(&[ATT_OP_VALUE_NOTIFICATION]) | tag!(&[ATT_OP_VALUE_INDICATION])) >>
         handle: le_u16 >>
-        value: many1!(complete!(le_u8)) >>
+        value: many0!(complete!(le_u8)) >>
         (
-           ValueNotification { handle, value }
+           ValueNotification { handle, value, indication: op[0] == ATT_OP_VALUE_INDICATION }
         )
 ));
 
@@ -160,6 +426,9 @@ pub fn characteristics(i: &[u8]) -> IResult<&[u8], Result<Vec<Characteristic>, E
         }
         ATT_OP_READ_BY_TYPE_RESP => {
             let (i, rec_len) = try_parse!(i, le_u8);
+            if rec_len == 0 {
+                return Err(Err::Error(error_position!(i, ErrorKind::Custom(4))));
+            }
             let num = i.len() / rec_len as usize;
             let b16_uuid = rec_len == 7;
             try_parse!(i, map!(count!(apply!(characteristic, b16_uuid), num), |r| Ok(r)))
@@ -173,6 +442,134 @@ pub fn characteristics(i: &[u8]) -> IResult<&[u8], Result<Vec<Characteristic>, E
     Ok((i, result))
 }
 
+fn service(i: &[u8], b16_uuid: bool, primary: bool) -> IResult<&[u8], Service> {
+    let (i, start_handle) = try_parse!(i, le_u16);
+    let (i, end_handle) = try_parse!(i, le_u16);
+    let (i, uuid) = if b16_uuid {
+        try_parse!(i, map!(le_u16, UUID::B16))
+    } else {
+        try_parse!(i, map!(parse_uuid_128, UUID::B128))
+    };
+
+    Ok((i, Service {
+        start_handle,
+        end_handle,
+        uuid,
+        primary,
+        characteristics: BTreeSet::new(),
+    }))
+}
+
+pub fn services(i: &[u8], primary: bool) -> IResult<&[u8], Result<Vec<Service>, ErrorResponse>> {
+    let (i, opcode) = try_parse!(i, le_u8);
+
+    let (i, result) = match opcode {
+        ATT_OP_ERROR_RESP => {
+            try_parse!(i, map!(error_response, Err))
+        }
+        ATT_OP_READ_BY_GROUP_RESP => {
+            let (i, rec_len) = try_parse!(i, le_u8);
+            if rec_len == 0 {
+                return Err(Err::Error(error_position!(i, ErrorKind::Custom(4))));
+            }
+            let num = i.len() / rec_len as usize;
+            let b16_uuid = rec_len == 6;
+            try_parse!(i, map!(count!(apply!(service, b16_uuid, primary), num), Ok))
+        }
+        x => {
+            warn!("unhandled services op type {} for {:?}", x, i);
+            (&[][..], Ok(vec![]))
+        }
+    };
+
+    Ok((i, result))
+}
+
+/// The opcode of the response the device sends to a request with opcode `request`, if any.
+pub fn response_opcode(request: u8) -> Option<u8> {
+    match request {
+        ATT_OP_EXCHANGE_MTU_REQ => Some(ATT_OP_EXCHANGE_MTU_RESP),
+        ATT_OP_FIND_INFO_REQ => Some(ATT_OP_FIND_INFO_RESP),
+        ATT_OP_FIND_BY_TYPE_REQ => Some(ATT_OP_FIND_BY_TYPE_RESP),
+        ATT_OP_READ_BY_TYPE_REQ => Some(ATT_OP_READ_BY_TYPE_RESP),
+        ATT_OP_READ_REQ => Some(ATT_OP_READ_RESP),
+        ATT_OP_READ_BLOB_REQ => Some(ATT_OP_READ_BLOB_RESP),
+        ATT_OP_READ_MULTI_REQ => Some(ATT_OP_READ_MULTI_RESP),
+        ATT_OP_READ_BY_GROUP_REQ => Some(ATT_OP_READ_BY_GROUP_RESP),
+        ATT_OP_WRITE_REQ => Some(ATT_OP_WRITE_RESP),
+        ATT_OP_PREP_WRITE_REQ => Some(ATT_OP_PREP_WRITE_RESP),
+        ATT_OP_EXEC_WRITE_REQ => Some(ATT_OP_EXEC_WRITE_RESP),
+        _ => None,
+    }
+}
+
+/// Turns an error response PDU into the corresponding `Error::Att`, passing any other PDU through.
+pub fn check_response(data: Vec<u8>) -> Result<Vec<u8>, Error> {
+    if data.first() != Some(&ATT_OP_ERROR_RESP) {
+        return Ok(data);
+    }
+
+    match error_response(&data[1..]) {
+        Ok((_, err)) => Err(err.into()),
+        Err(err) => Err(Error::Other(format!("failed to parse error response {:?}: {:?}",
+                                             data, err))),
+    }
+}
+
+pub fn read_by_group_type_req(start_handle: u16, end_handle: u16, uuid: UUID) -> Vec<u8> {
+    let mut buf = BytesMut::with_capacity(5 + uuid.size());
+    buf.put_u8(ATT_OP_READ_BY_GROUP_REQ);
+    buf.put_u16_le(start_handle);
+    buf.put_u16_le(end_handle);
+    match uuid {
+        UUID::B16(u) => buf.put_u16_le(u),
+        UUID::B128(u) => buf.put_slice(&u),
+    }
+    buf.to_vec()
+}
+
+fn descriptor(i: &[u8], b16_uuid: bool) -> IResult<&[u8], Descriptor> {
+    let (i, handle) = try_parse!(i, le_u16);
+    let (i, uuid) = if b16_uuid {
+        try_parse!(i, map!(le_u16, UUID::B16))
+    } else {
+        try_parse!(i, map!(parse_uuid_128, UUID::B128))
+    };
+
+    Ok((i, Descriptor { handle, uuid }))
+}
+
+pub fn descriptors(i: &[u8]) -> IResult<&[u8], Result<Vec<Descriptor>, ErrorResponse>> {
+    let (i, opcode) = try_parse!(i, le_u8);
+
+    let (i, result) = match opcode {
+        ATT_OP_ERROR_RESP => {
+            try_parse!(i, map!(error_response, Err))
+        }
+        ATT_OP_FIND_INFO_RESP => {
+            // format 1 is a list of handles with 16-bit UUIDs, format 2 with 128-bit UUIDs
+            let (i, format) = try_parse!(i, le_u8);
+            let b16_uuid = format == 1;
+            let num = i.len() / if b16_uuid { 4 } else { 18 };
+            try_parse!(i, map!(count!(apply!(descriptor, b16_uuid), num), Ok))
+        }
+        x => {
+            warn!("unhandled descriptors op type {} for {:?}", x, i);
+            (&[][..], Ok(vec![]))
+        }
+    };
+
+    Ok((i, result))
+}
+
+pub fn find_information_req(start_handle: u16, end_handle: u16) -> Vec<u8> {
+    let mut buf = BytesMut::with_capacity(5);
+    buf.put_u8(ATT_OP_FIND_INFO_REQ);
+    buf.put_u16_le(start_handle);
+    buf.put_u16_le(end_handle);
+    buf.to_vec()
+}
+
 pub fn read_by_type_req(start_handle: u16, end_handle: u16, uuid: UUID) -> Vec<u8> {
     let mut buf = BytesMut::with_capacity(3 + uuid.size());
     buf.put_u8(ATT_OP_READ_BY_TYPE_REQ);
@@ -191,3 +588,49 @@ pub fn read_req(handle: u16) -> Vec<u8> {
     buf.put_u16_le(handle);
     buf.to_vec()
 }
+
+pub fn read_blob_req(handle: u16, offset: u16) -> Vec<u8> {
+    let mut buf = BytesMut::with_capacity(5);
+    buf.put_u8(ATT_OP_READ_BLOB_REQ);
+    buf.put_u16_le(handle);
+    buf.put_u16_le(offset);
+    buf.to_vec()
+}
+
+pub fn prepare_write_req(handle: u16, offset: u16, value: &[u8]) -> Vec<u8> {
+    let mut buf = BytesMut::with_capacity(5 + value.len());
+    buf.put_u8(ATT_OP_PREP_WRITE_REQ);
+    buf.put_u16_le(handle);
+    buf.put_u16_le(offset);
+    buf.put_slice(value);
+    buf.to_vec()
+}
+
+/// The part of `value` starting at `offset` that fits in a single prepare write request, which
+/// holds the opcode, handle and offset along with the value.
+pub fn prepare_write_chunk(value: &[u8], offset: usize, mtu: usize) -> &[u8] {
+    &value[offset..value.len().min(offset + mtu - 5)]
+}
+
+pub fn execute_write_req(commit: bool) -> Vec<u8> {
+    let mut buf = BytesMut::with_capacity(2);
+    buf.put_u8(ATT_OP_EXEC_WRITE_REQ);
+    buf.put_u8(if commit { ATT_EXEC_WRITE_COMMIT } else { ATT_EXEC_WRITE_CANCEL });
+    buf.to_vec()
+}
+
+pub fn exchange_mtu_req(client_rx_mtu: u16) -> Vec<u8> {
+    let mut buf = BytesMut::with_capacity(3);
+    buf.put_u8(ATT_OP_EXCHANGE_MTU_REQ);
+    buf.put_u16_le(client_rx_mtu);
+    buf.to_vec()
+}
+
+pub fn error_resp(request_opcode: u8, handle: u16, code: AttError) -> Vec<u8> {
+    let mut buf = BytesMut::with_capacity(5);
+    buf.put_u8(ATT_OP_ERROR_RESP);
+    buf.put_u8(request_opcode);
+    buf.put_u16_le(handle);
+    buf.put_u8(code.num());
+    buf.to_vec()
+}
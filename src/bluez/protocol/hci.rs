@@ -1,9 +1,9 @@
-use nom::{le_u8, le_u16, le_u32, le_u64, le_i8, IResult, Err, ErrorKind};
+use nom::{le_u8, le_u16, le_u32, le_u64, le_i8, rest, IResult, Err, ErrorKind};
 use num::FromPrimitive;
 use bytes::{BytesMut, BufMut};
 
 
-use ::api::{BDAddr, AddressType};
+use ::api::{BDAddr, DataLength, LEFeatureFlags, Phy, RemoteVersion, ScanParameters};
 use bluez::constants::*;
 use bluez::protocol::*;
 
@@ -20,7 +20,7 @@ mod tests {
         let buf = [4,62,40,2,1,4,0,192,74,150,234,218,116,28,18,9,76,69,68,66,
             108,117,101,45,69,65,57,54,52,65,67,48,32,5,18,16,0,20,0,2,10,4,190];
 
-        let expected = Message::LEAdvertisingReport(
+        let expected = Message::LEAdvertisingReport(vec![
             LEAdvertisingInfo {
                 evt_type: 4,
                 bdaddr_type: 0,
@@ -31,9 +31,10 @@ mod tests {
                     LocalName(String::from("LEDBlue-EA964AC0 ")),
                     SlaveConnectionIntervalRange(16, 20),
                     TxPowerLevel(4),
-                ]
+                ],
+                rssi: -66,
             }
-        );
+        ]);
 
         assert_eq!(message(&buf), Ok((&[][..], expected)));
     }
@@ -43,7 +44,7 @@ mod tests {
         let buf = [4, 62, 23, 2, 1, 0, 0, 192, 74, 150, 234, 218, 116, 11, 2,
             1, 6, 7, 2, 240, 255, 229, 255, 224, 255, 194];
 
-        let expected = Message::LEAdvertisingReport(
+        let expected = Message::LEAdvertisingReport(vec![
             LEAdvertisingInfo {
                 evt_type: 0,
                 bdaddr_type: 0,
@@ -56,9 +57,43 @@ mod tests {
                     ServiceClassUUID16(0xFFF0),
                     ServiceClassUUID16(0xFFE5),
                     ServiceClassUUID16(0xFFE0),
-                ]
+                ],
+                rssi: -62,
             }
-        );
+        ]);
+
+        assert_eq!(message(&buf), Ok((&[][..], expected)));
+    }
+
+    #[test]
+    fn test_decode_multiple_advertising_reports() {
+        let buf = [4, 62, 25, 2, 2,
+            0, 0, 192, 74, 150, 234, 218, 116, 3, 2, 1, 6, 200,
+            4, 1, 1, 2, 3, 4, 5, 6, 0, 190];
+
+        let expected = Message::LEAdvertisingReport(vec![
+            LEAdvertisingInfo {
+                evt_type: 0,
+                bdaddr_type: 0,
+                bdaddr: BDAddr {
+                    address: [192, 74, 150, 234, 218, 116],
+                },
+                data: vec![
+                    Flags(AdvertisingFlags::BR_EDR_NOT_SUPPORTED |
+                        AdvertisingFlags::LE_GENERAL_DISCOVERABLE_MODE),
+                ],
+                rssi: -56,
+            },
+            LEAdvertisingInfo {
+                evt_type: 4,
+                bdaddr_type: 1,
+                bdaddr: BDAddr {
+                    address: [1, 2, 3, 4, 5, 6],
+                },
+                data: vec![],
+                rssi: -66,
+            },
+        ]);
 
         assert_eq!(message(&buf), Ok((&[][..], expected)));
     }
@@ -72,7 +107,7 @@ mod tests {
 
     #[test]
     fn test_le_advertising_info() {
-        let buf = [1,4,0,192,74,150,234,218,116,11,2,1,6,7,2,240,255,229,255,224,255];
+        let buf = [4,0,192,74,150,234,218,116,11,2,1,6,7,2,240,255,229,255,224,255,181];
 
         assert_eq!(le_advertising_info(&buf), Ok((&[][..], LEAdvertisingInfo {
             evt_type: 4,
@@ -86,6 +121,7 @@ mod tests {
                 ServiceClassUUID16(65520),
                 ServiceClassUUID16(65509),
                 ServiceClassUUID16(65504)],
+            rssi: -75,
         })));
     }
 
@@ -101,6 +137,14 @@ mod tests {
         let buf = [18,9,76,69,68,66,108,117,101,45,69,65,57,55,66,55,65,51,32];
         assert_eq!(le_advertising_data(&buf), Ok((&[][..], vec![
             LocalName(String::from("LEDBlue-EA97B7A3 "))])));
+
+        let buf = [9, 5, 1, 2, 3, 4, 5, 6, 7, 8];
+        assert_eq!(le_advertising_data(&buf), Ok((&[][..], vec![
+            ServiceClassUUID32(0x04030201), ServiceClassUUID32(0x08070605)])));
+
+        let buf = [5, 0xFF, 0x4C, 0x00, 2, 21];
+        assert_eq!(le_advertising_data(&buf), Ok((&[][..], vec![
+            ManufacturerSpecific(0x004C, vec![2, 21])])));
     }
 
 
@@ -116,6 +160,8 @@ mod tests {
         assert!(le_advertising_data(&buf).is_err());
         let buf = [3, 0xFE];
         assert!(le_advertising_data(&buf).is_err());
+        let buf = [2, 0xFF, 0x4C];
+        assert!(le_advertising_data(&buf).is_err());
     }
 
     #[test]
@@ -132,18 +178,327 @@ mod tests {
         )))
     }
 
+    #[test]
+    fn test_acl_data_continuation() {
+        let buf = [2, 64, 16, 3, 0, 7, 8, 9];
+        assert_eq!(message(&buf), Ok((
+            &[][..],
+            Message::ACLDataContinuation {
+                handle: 64,
+                data: vec![7, 8, 9],
+            },
+        )))
+    }
+
+    #[test]
+    fn test_le_data_length_change() {
+        let buf = [4, 62, 11, 7, 64, 0, 251, 0, 72, 8, 251, 0, 72, 8];
+        assert_eq!(message(&buf), Ok((
+            &[][..],
+            Message::LEDataLengthChange {
+                handle: 64,
+                lengths: DataLength {
+                    tx_octets: 251,
+                    tx_time: 2120,
+                    rx_octets: 251,
+                    rx_time: 2120,
+                },
+            }
+        )));
+    }
+
+    #[test]
+    fn test_le_phy_update_complete() {
+        let buf = [4, 62, 6, 12, 0, 64, 0, 2, 2];
+        assert_eq!(message(&buf), Ok((
+            &[][..],
+            Message::LEPhyUpdate {
+                status: HCIStatus::Success,
+                handle: 64,
+                tx_phy: Phy::Le2M,
+                rx_phy: Phy::Le2M,
+            }
+        )));
+    }
+
+    #[test]
+    fn test_read_remote_version_complete() {
+        let buf = [4, 12, 8, 0, 64, 0, 9, 15, 0, 0x34, 0x12];
+        assert_eq!(message(&buf), Ok((
+            &[][..],
+            Message::ReadRemoteVersionComplete {
+                status: HCIStatus::Success,
+                handle: 64,
+                version: RemoteVersion { version: 9, manufacturer: 15, subversion: 0x1234 },
+            }
+        )));
+    }
+
     #[test]
     fn test_cmd_status() {
         let buf = [4, 15, 4, 0, 1, 22, 32];
         assert_eq!(message(&buf), Ok((
             &[][..],
             Message::CommandStatus {
-                command: CommandType::LEReadRemoteUsedFeatures,
-                status: HCIStatus::Success,
+                credits: 1,
+                opcode: CommandType::LEReadRemoteUsedFeatures as u16,
+                status: 0,
+            }
+        )));
+
+        // statuses we don't have a name for still need to reach the command's caller
+        let buf = [4, 15, 4, 0x45, 1, 0x36, 0x20];
+        assert_eq!(message(&buf), Ok((
+            &[][..],
+            Message::CommandStatus {
+                credits: 1,
+                opcode: 0x2036,
+                status: 0x45,
+            }
+        )));
+    }
+
+    #[test]
+    fn test_vendor_cmd_complete() {
+        let buf = [4, 14, 6, 1, 0x01, 0xfc, 0, 0xaa, 0xbb];
+        assert_eq!(message(&buf), Ok((
+            &[][..],
+            Message::HCICommandComplete {
+                credits: 1,
+                opcode: 0xfc01,
+                status: 0,
+                parameters: vec![0xaa, 0xbb],
+            }
+        )));
+    }
+
+    #[test]
+    fn test_read_rssi_complete() {
+        let buf = [4, 14, 7, 1, 5, 20, 0, 64, 0, 196];
+        assert_eq!(message(&buf), Ok((
+            &[][..],
+            Message::HCICommandComplete {
+                credits: 1,
+                opcode: CommandType::ReadRSSI as u16,
+                status: 0,
+                parameters: vec![64, 0, 196],
+            }
+        )));
+        assert_eq!(command_complete(CommandType::ReadRSSI as u16, 0, &buf[7..]), Ok((
+            &[][..],
+            CommandComplete::ReadRSSI {
+                status: 0,
+                handle: 64,
+                rssi: -60,
+            }
+        )));
+    }
+
+    #[test]
+    fn test_read_local_version_complete() {
+        let buf = [9, 0x0d, 0x01, 9, 2, 0, 0x0e, 0x22];
+        assert_eq!(command_complete(CommandType::ReadLocalVersion as u16, 0, &buf), Ok((
+            &[][..],
+            CommandComplete::ReadLocalVersion {
+                hci_version: 9,
+                hci_revision: 0x010d,
+                lmp_version: 9,
+                manufacturer: 2,
+                lmp_sub_version: 0x220e,
+            }
+        )));
+    }
+
+    #[test]
+    fn test_read_buffer_size_complete() {
+        let buf = [0xfd, 0x03, 0x40, 0x08, 0x00, 0x08, 0x00];
+        assert_eq!(command_complete(CommandType::ReadBufferSize as u16, 0, &buf), Ok((
+            &[][..],
+            CommandComplete::ReadBufferSize {
+                acl_packet_length: 1021,
+                sco_packet_length: 64,
+                acl_packets: 8,
+                sco_packets: 8,
+            }
+        )));
+    }
+
+    #[test]
+    fn test_le_read_buffer_size_complete() {
+        let buf = [0xfb, 0x00, 0x0f];
+        assert_eq!(command_complete(CommandType::LEReadBufferSize as u16, 0, &buf), Ok((
+            &[][..],
+            CommandComplete::LEReadBufferSize {
+                status: 0,
+                packet_length: 251,
+                packets: 15,
+            }
+        )));
+    }
+
+    #[test]
+    fn test_le_read_local_supported_features_complete() {
+        let buf = [0x21, 0x01, 0, 0, 0, 0, 0, 0];
+        assert_eq!(command_complete(CommandType::LEReadLocalSupportedFeatures as u16, 0, &buf),
+                   Ok((
+            &[][..],
+            CommandComplete::LEReadLocalSupportedFeatures {
+                status: 0,
+                features: LEFeatureFlags::LE_ENCRYPTION |
+                    LEFeatureFlags::DATA_PACKET_LENGTH_EXTENSION | LEFeatureFlags::LE_2M_PHY,
+            }
+        )));
+    }
+
+    #[test]
+    fn test_le_read_supported_states_complete() {
+        let buf = [0xff, 0xff, 0xff, 0xff, 0xff, 0x03, 0, 0];
+        assert_eq!(command_complete(CommandType::LEReadSupportedStates as u16, 0, &buf), Ok((
+            &[][..],
+            CommandComplete::LEReadSupportedStates {
+                status: 0,
+                states: 0x03ff_ffff_ffff,
             }
         )));
     }
 
+    #[test]
+    fn test_read_white_list_size_complete() {
+        let buf = [4, 14, 5, 1, 15, 32, 0, 8];
+        let (_, message) = message(&buf).unwrap();
+        match message {
+            Message::HCICommandComplete { opcode, status, parameters, .. } => {
+                assert_eq!(command_complete(opcode, status, &parameters), Ok((
+                    &[][..],
+                    CommandComplete::LEReadWhiteListSize {
+                        status: 0,
+                        size: 8,
+                    }
+                )));
+            }
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_valid_scan_window() {
+        assert!(valid_scan_window(0x0004, 0x0004));
+        assert!(valid_scan_window(0x4000, 0x4000));
+        assert!(valid_scan_window(0x4000, 0x0004));
+        assert!(!valid_scan_window(0x0003, 0x0003));
+        assert!(!valid_scan_window(0x4001, 0x0010));
+        assert!(!valid_scan_window(0x0010, 0x0003));
+        // the window can't be longer than the interval
+        assert!(!valid_scan_window(0x0010, 0x0011));
+    }
+
+    #[test]
+    fn test_valid_connection_parameters() {
+        assert!(valid_connection_parameters(0x0006, 0x0006, 0, 0x000A));
+        assert!(valid_connection_parameters(0x0018, 0x0028, 0, 0x002A));
+        assert!(valid_connection_parameters(0x0C80, 0x0C80, 0, 0x0C80));
+
+        // intervals
+        assert!(!valid_connection_parameters(0x0005, 0x0006, 0, 0x000A));
+        assert!(!valid_connection_parameters(0x0007, 0x0006, 0, 0x000A));
+        assert!(!valid_connection_parameters(0x0006, 0x0C81, 0, 0x0C80));
+
+        // latency
+        assert!(valid_connection_parameters(0x0006, 0x0006, 0x01F3, 0x0C80));
+        assert!(!valid_connection_parameters(0x0006, 0x0006, 0x01F4, 0x0C80));
+
+        // supervision timeout
+        assert!(!valid_connection_parameters(0x0006, 0x0006, 0, 0x0009));
+        assert!(!valid_connection_parameters(0x0006, 0x0006, 0, 0x0C81));
+
+        // the timeout must exceed (1 + latency) * max_interval * 2, here 1000 and 8000 ms
+        assert!(!valid_connection_parameters(0x0006, 0x0064, 3, 0x0064));
+        assert!(valid_connection_parameters(0x0006, 0x0064, 3, 0x0065));
+        assert!(!valid_connection_parameters(0x0006, 0x0C80, 0, 0x0320));
+        assert!(valid_connection_parameters(0x0006, 0x0C80, 0, 0x0321));
+    }
+
+    #[test]
+    fn test_scan_presets() {
+        let presets = [
+            (ScanParameters::low_latency(), 6554, 6554, true),
+            (ScanParameters::balanced(), 6554, 1638, true),
+            (ScanParameters::low_power(), 8192, 819, false),
+        ];
+        for &(ref params, interval, window, active) in presets.iter() {
+            assert_eq!((params.interval, params.window, params.active),
+                       (interval, window, active));
+            assert!(valid_scan_window(params.interval, params.window));
+        }
+
+        let default = ScanParameters::default();
+        assert!(valid_scan_window(default.interval, default.window));
+    }
+
+    #[test]
+    fn test_le_scan_parameters() {
+        assert_eq!(&le_scan_parameters(&ScanParameters::balanced())[..],
+                   &[1, 0x9A, 0x19, 0x66, 0x06, 0, 0][..]);
+        assert_eq!(&le_scan_parameters(&ScanParameters::low_power())[..],
+                   &[0, 0x00, 0x20, 0x33, 0x03, 0, 0][..]);
+    }
+
+    #[test]
+    fn test_le_conn_complete() {
+        let buf = [4, 62, 19, 1, 0, 64, 0, 0, 1, 1, 2, 3, 4, 5, 6, 24, 0, 0, 0, 42, 0, 0];
+        assert_eq!(message(&buf), Ok((
+            &[][..],
+            Message::LEConnComplete(LEConnInfo {
+                status: HCIStatus::Success,
+                handle: 64,
+                role: 0,
+                bdaddr: BDAddr { address: [1, 2, 3, 4, 5, 6] },
+                bdaddr_type: 1,
+                interval: 24,
+                latency: 0,
+                supervision_timeout: 42,
+                master_clock_accuracy: 0,
+            })
+        )));
+    }
+
+    #[test]
+    fn test_le_enhanced_conn_complete() {
+        let buf = [4, 62, 31, 10, 0, 64, 0, 0, 3, 1, 2, 3, 4, 5, 6,
+            0, 0, 0, 0, 0, 0, 11, 12, 13, 14, 15, 0x56, 24, 0, 0, 0, 42, 0, 0];
+        assert_eq!(message(&buf), Ok((
+            &[][..],
+            Message::LEConnComplete(LEConnInfo {
+                status: HCIStatus::Success,
+                handle: 64,
+                role: 0,
+                bdaddr: BDAddr { address: [1, 2, 3, 4, 5, 6] },
+                bdaddr_type: 1,
+                interval: 24,
+                latency: 0,
+                supervision_timeout: 42,
+                master_clock_accuracy: 0,
+            })
+        )));
+    }
+
+    #[test]
+    fn test_event_masks() {
+        let (event_mask, le_event_mask) = event_masks(false, false);
+        for bit in &[2, 3, 4, 7, 11, 15, 25, 47, 61] {
+            assert_ne!(event_mask & 1 << bit, 0, "event {} is masked", bit);
+        }
+        for bit in &[0, 1, 2, 3, 4, 9] {
+            assert_ne!(le_event_mask & 1 << bit, 0, "LE event {} is masked", bit);
+        }
+        assert_eq!(le_event_mask & (1 << 6 | 1 << 11), 0);
+
+        let (_, le_event_mask) = event_masks(true, false);
+        assert_eq!(le_event_mask & (1 << 6 | 1 << 11), 1 << 6);
+        let (_, le_event_mask) = event_masks(false, true);
+        assert_eq!(le_event_mask & (1 << 6 | 1 << 11), 1 << 11);
+    }
+
     #[test]
     fn test_recv_le_meta() {
         let buf = [4, 62, 12, 4, 0, 64, 0, 1, 0, 0, 0, 0, 0, 0, 0];
@@ -166,39 +521,32 @@ pub struct ACLData {
     pub len: u16,
 }
 
-bitflags! {
-    pub struct LEFeatureFlags: u64 {
-        const LE_ENCRYPTION = 0x0001;
-        const CONNECTION_PARAMETERS_REQUEST_PROCEDURE = 0x0002;
-        const EXTENDED_REJECT_INDICATION = 0x0004;
-        const SLAVE_INITIATED_FEATURES_EXCHANGE = 0x0008;
-        const PING = 0x0010;
-        const DATA_PACKET_LENGTH_EXTENSION = 0x0020;
-        const LL_PRIVACY = 0x0040;
-        const EXTENDED_SCANNER_FILTER_POLICIES = 0x0080;
-        const LE_2M_PHY = 0x0100;
-        const STABLE_MODULATION_INDEX_TX = 0x0200;
-        const STABLE_MODULATION_INDEX_RX = 0x0400;
-        const LE_CODED_PHY = 0x0800;
-        const LE_EXTENDED_ADVERTISING = 0x1000;
-        const LE_PERIODIC_ADVERTISING = 0x2000;
-        const CHANNEL_SELECTION_ALGORITHM_2 = 0x4000;
-        const POWER_CLASS_1 = 0x8000;
-        const MINIMUM_NUMBER_OF_USED_CHANNELS_PROCEDURE = 0x10000;
-    }
-}
-
 #[derive(Debug, PartialEq)]
 pub enum Message {
-    LEAdvertisingReport(LEAdvertisingInfo),
+    LEAdvertisingReport(Vec<LEAdvertisingInfo>),
     LEConnComplete(LEConnInfo),
     LEConnUpdate(LEConnUpdateInfo),
+    LEDataLengthChange {
+        handle: u16,
+        lengths: DataLength,
+    },
+    LEPhyUpdate {
+        status: HCIStatus,
+        handle: u16,
+        tx_phy: Phy,
+        rx_phy: Phy,
+    },
     LEReadRemoteUsedFeaturesComplete {
         status: HCIStatus,
         handle: u16,
         flags: LEFeatureFlags,
     },
-    HCICommandComplete(CommandComplete),
+    HCICommandComplete {
+        credits: u8,
+        opcode: u16,
+        status: u8,
+        parameters: Vec<u8>,
+    },
     LEScanEnableCommand {
         enable: bool,
         filter_duplicates: bool,
@@ -213,8 +561,14 @@ pub enum Message {
         reason: HCIStatus,
     },
     CommandStatus {
-        command: CommandType,
+        credits: u8,
+        opcode: u16,
+        status: u8,
+    },
+    ReadRemoteVersionComplete {
         status: HCIStatus,
+        handle: u16,
+        version: RemoteVersion,
     },
     ACLDataPacket(ACLData),
     ACLDataContinuation {
@@ -240,6 +594,7 @@ bitflags! {
 pub enum LEAdvertisingData {
     Flags(AdvertisingFlags),
     ServiceClassUUID16(u16),
+    ServiceClassUUID32(u32),
     ServiceClassUUID128([u8; 16]),
     LocalName(String),
     TxPowerLevel(i8),
@@ -250,7 +605,7 @@ pub enum LEAdvertisingData {
     ServiceData32(u32, Vec<u8>),
     ServiceData128([u8; 16], Vec<u8>),
     SolicitationUUID32(u32),
-    ManufacturerSpecific(Vec<u8>),
+    ManufacturerSpecific(u16, Vec<u8>),
 }
 
 #[derive(Debug, PartialEq)]
@@ -258,11 +613,13 @@ pub struct LEAdvertisingInfo {
     pub evt_type: u8,
     pub bdaddr_type: u8,
     pub bdaddr: BDAddr,
-    pub data: Vec<LEAdvertisingData>
+    pub data: Vec<LEAdvertisingData>,
+    pub rssi: i8,
 }
 
 #[derive(Debug, PartialEq)]
 pub struct LEConnInfo {
+    pub status: HCIStatus,
     pub handle: u16,
     pub role: u8,
     pub bdaddr: BDAddr,
@@ -284,7 +641,7 @@ pub struct LEConnUpdateInfo {
 
 
 enum_from_primitive! {
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[repr(u8)]
 pub enum HCIStatus {
     ACLConnectionAlreadyExists = 0x0B,
@@ -371,6 +728,7 @@ enum_from_primitive! {
 enum HCIEventSubType {
     DisconnComplete = 0x05,
     EncryptChange = 0x08,
+    ReadRemoteVersionComplete = 0x0c,
     CmdComplete = 0x0e,
     CmdStatus = 0x0f,
     LEMetaEvent = 0x3e,
@@ -385,16 +743,21 @@ enum LEEventType {
     LEAdvertisingReport = 2,
     LEConnUpdateComplete = 3,
     LEReadRemoteUsedFeaturesComplete = 4,
+    LEDataLengthChange = 7,
+    LEEnhancedConnComplete = 10,
+    LEPhyUpdateComplete = 12,
 }}
 
 enum_from_primitive! {
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Copy, Clone)]
 #[repr(u16)]
 pub enum CommandType {
     Reset = OCF_RESET as u16 | (OGF_HOST_CTL as u16) << 10,
+    SetEventMask = OCF_SET_EVENT_MASK | (OGF_HOST_CTL as u16) << 10,
     ReadLEHostSupported = OCF_READ_LE_HOST_SUPPORTED | (OGF_HOST_CTL as u16) << 10,
     WriteLEHostSupported = OCF_WRITE_LE_HOST_SUPPORTED | (OGF_HOST_CTL as u16) << 10,
     ReadLocalVersion = OCF_READ_LOCAL_VERSION | (OGF_INFO_PARAM as u16) << 10,
+    ReadBufferSize = OCF_READ_BUFFER_SIZE | (OGF_INFO_PARAM as u16) << 10,
     ReadBDAddr = OCF_READ_BD_ADDR | (OGF_INFO_PARAM as u16) << 10,
     ReadRSSI = OCF_READ_RSSI | (OGF_STATUS_PARAM as u16) << 10,
 
@@ -408,13 +771,26 @@ pub enum CommandType {
     LEConnectionUpdate = OCF_LE_CONN_UPDATE | (OGF_LE_CTL as u16) << 10,
     LEStartEncryption = OCF_LE_START_ENCRYPTION | (OGF_LE_CTL as u16) << 10,
 
+    LEReadBufferSize = 0x2002,
+    LEReadLocalSupportedFeatures = 0x2003,
     LESetAdvertisingData = 0x2008,
     LESetScanResponseData = 0x2009,
+    LECreateConnectionCancel = 0x200E,
+    LEReadWhiteListSize = 0x200F,
+    LEClearWhiteList = 0x2010,
     LEAddDeviceToWhiteList = 0x2011,
     LERemoveDeviceFromWhiteList = 0x2012,
     LEReadRemoteUsedFeatures = 0x2016,
+    LEReadSupportedStates = 0x201C,
+    LESetDataLength = 0x2022,
+    LEReadSuggestedDefaultDataLength = 0x2023,
+    LEReadMaximumDataLength = 0x202F,
+    LEReadPHY = 0x2030,
+    LESetDefaultPHY = 0x2031,
+    LESetPHY = 0x2032,
 
     Disconnect = 0x0406,
+    ReadRemoteVersionInformation = 0x041D,
 }}
 
 #[allow(dead_code)]
@@ -425,24 +801,59 @@ pub enum CommandComplete {
     ReadLocalVersion {
         hci_version: u8,
         hci_revision: u16,
-        lmp_version: i8,
+        lmp_version: u8,
         manufacturer: u16,
-        lmp_sub_version: u8,
+        lmp_sub_version: u16,
+    },
+    ReadBufferSize {
+        acl_packet_length: u16,
+        sco_packet_length: u8,
+        acl_packets: u16,
+        sco_packets: u16,
     },
     ReadBDAddr {
-        address_type: AddressType,
         address: BDAddr,
     },
-    LESetScanParameters,
-    LESetScanEnabled {
-        enabled: bool,
+    LEReadBufferSize {
+        status: u8,
+        packet_length: u16,
+        packets: u8,
     },
+    LEReadLocalSupportedFeatures {
+        status: u8,
+        features: LEFeatureFlags,
+    },
+    LEReadSupportedStates {
+        status: u8,
+        states: u64,
+    },
+    LESetScanParameters,
     ReadRSSI {
+        status: u8,
+        handle: u16,
+        rssi: i8,
+    },
+    LEReadWhiteListSize {
+        status: u8,
+        size: u8,
+    },
+    LEReadSuggestedDefaultDataLength {
+        status: u8,
+        tx_octets: u16,
+        tx_time: u16,
+    },
+    LEReadMaximumDataLength {
+        status: u8,
+        lengths: DataLength,
+    },
+    LEReadPHY {
+        status: u8,
         handle: u16,
-        rssi: u8
+        tx_phy: u8,
+        rx_phy: u8,
     },
     Other {
-        command: CommandType,
+        opcode: u16,
         status: u8,
         data: Vec<u8>
     }
@@ -464,11 +875,13 @@ fn le_advertising_data(i: &[u8]) -> IResult<&[u8], Vec<LEAdvertisingData>> {
             try_parse!(i, map!(le_u8, |u| vec![Flags(AdvertisingFlags::from_bits_truncate(u))]))
         }
         0x02|0x03 =>  {
-            try_parse!(i, count!(map!(le_u16, |u| ServiceClassUUID16(u)), len / 2))
+            try_parse!(i, count!(map!(le_u16, ServiceClassUUID16), len / 2))
+        }
+        0x04|0x05 => {
+            try_parse!(i, count!(map!(le_u32, ServiceClassUUID32), len / 4))
         }
         0x06|0x07 => {
-            try_parse!(i, count!(map!(parse_uuid_128,
-                |b| ServiceClassUUID128(b)), len / 16))
+            try_parse!(i, count!(map!(parse_uuid_128, ServiceClassUUID128), len / 16))
         }
         0x08|0x09 => {
             try_parse!(i, map!(take!(len),
@@ -485,11 +898,10 @@ fn le_advertising_data(i: &[u8]) -> IResult<&[u8], Vec<LEAdvertisingData>> {
             ))
         }
         0x14 => {
-            try_parse!(i, count!(map!(le_u16, |u| SolicitationUUID16(u)), len / 2))
+            try_parse!(i, count!(map!(le_u16, SolicitationUUID16), len / 2))
         }
         0x15 => {
-            try_parse!(i, count!(map!(parse_uuid_128,
-                |b| SolicitationUUID128(b)), len / 16))
+            try_parse!(i, count!(map!(parse_uuid_128, SolicitationUUID128), len / 16))
         }
         0x16 => {
             if len < 2 {
@@ -520,11 +932,16 @@ fn le_advertising_data(i: &[u8]) -> IResult<&[u8], Vec<LEAdvertisingData>> {
                 (vec![ServiceData128(uuid, data)])))
         }
         0x1F => {
-            try_parse!(i, count!(map!(le_u32,
-                |b| SolicitationUUID32(b)), len / 4))
+            try_parse!(i, count!(map!(le_u32, SolicitationUUID32), len / 4))
         }
         0xFF => {
-            try_parse!(i, map!(count!(le_u8, len), |b| vec![ManufacturerSpecific(b)]))
+            if len < 2 {
+                return Err(Err::Error(error_position!(i, ErrorKind::Custom(4))));
+            }
+            try_parse!(i, do_parse!(
+                company_id: le_u16 >>
+                data: count!(le_u8, len - 2) >>
+                (vec![ManufacturerSpecific(company_id, data)])))
         }
         _ => {
             // skip this field
@@ -541,8 +958,6 @@ fn le_advertising_data(i: &[u8]) -> IResult<&[u8], Vec<LEAdvertisingData>> {
 
 named!(le_advertising_info<&[u8], LEAdvertisingInfo>,
     do_parse!(
-       // TODO: support counts other than 1
-       _count: le_u8 >>
        evt_type: le_u8 >>
        bdaddr_type: le_u8 >>
        bdaddr: bd_addr >>
@@ -550,13 +965,22 @@ named!(le_advertising_info<&[u8], LEAdvertisingInfo>,
            acc.extend(x);
            acc
        })) >>
+       rssi: le_i8 >>
        (
          LEAdvertisingInfo {
-           evt_type, bdaddr_type, bdaddr, data: data
+           evt_type, bdaddr_type, bdaddr, data, rssi
          }
        )
     ));
 
+// a single event can carry several reports, each laid out in full before the next
+named!(le_advertising_reports<&[u8], Vec<LEAdvertisingInfo>>,
+    do_parse!(
+       count: le_u8 >>
+       reports: count!(le_advertising_info, count as usize) >>
+       (reports)
+    ));
+
 named!(bd_addr<&[u8], BDAddr>,
     do_parse!(
       addr: take!(6) >> (
@@ -567,7 +991,7 @@ named!(bd_addr<&[u8], BDAddr>,
 
 named!(le_conn_complete<&[u8], LEConnInfo>,
     do_parse!(
-       _skip: le_u8 >>
+       status: map_opt!(le_u8, HCIStatus::from_u8) >>
        handle: le_u16 >>
        role: le_u8 >>
        bdaddr_type: le_u8 >>
@@ -578,7 +1002,29 @@ named!(le_conn_complete<&[u8], LEConnInfo>,
        master_clock_accuracy: le_u8 >>
        (
            LEConnInfo {
-              handle, role, bdaddr_type, bdaddr, interval, latency,
+              status, handle, role, bdaddr_type, bdaddr, interval, latency,
+              supervision_timeout, master_clock_accuracy
+           }
+       )));
+
+// sent instead of LE Connection Complete while it is unmasked; the resolvable private addresses
+// aren't needed, and the peer address type is 2 or 3 when it is an identity address
+named!(le_enhanced_conn_complete<&[u8], LEConnInfo>,
+    do_parse!(
+       status: map_opt!(le_u8, HCIStatus::from_u8) >>
+       handle: le_u16 >>
+       role: le_u8 >>
+       bdaddr_type: map!(le_u8, |t| t & 0x01) >>
+       bdaddr: bd_addr >>
+       _local_rpa: bd_addr >>
+       _peer_rpa: bd_addr >>
+       interval: le_u16 >>
+       latency: le_u16 >>
+       supervision_timeout: le_u16 >>
+       master_clock_accuracy: le_u8 >>
+       (
+           LEConnInfo {
+              status, handle, role, bdaddr_type, bdaddr, interval, latency,
               supervision_timeout, master_clock_accuracy
            }
        )));
@@ -611,14 +1057,49 @@ named!(le_conn_update_complete<&[u8], Message>,
         )
 ));
 
+named!(data_length<&[u8], DataLength>,
+    do_parse!(
+        tx_octets: le_u16 >>
+        tx_time: le_u16 >>
+        rx_octets: le_u16 >>
+        rx_time: le_u16 >>
+        (
+          DataLength { tx_octets, tx_time, rx_octets, rx_time }
+        )
+));
+
+named!(le_data_length_change<&[u8], Message>,
+    do_parse!(
+        handle: le_u16 >>
+        lengths: data_length >>
+        (
+          Message::LEDataLengthChange { handle, lengths }
+        )
+));
+
+named!(le_phy_update_complete<&[u8], Message>,
+    do_parse!(
+        status: map_opt!(le_u8, HCIStatus::from_u8) >>
+        handle: le_u16 >>
+        // the PHYs aren't meaningful if the update failed
+        tx_phy: map!(le_u8, |b| Phy::from_u8(b).unwrap_or_default()) >>
+        rx_phy: map!(le_u8, |b| Phy::from_u8(b).unwrap_or_default()) >>
+        (
+          Message::LEPhyUpdate { status, handle, tx_phy, rx_phy }
+        )
+));
+
 fn le_meta_event(i: &[u8]) -> IResult<&[u8], Message> {
     let (i, le_type) = try_parse!(i, map_opt!(le_u8, |b| LEEventType::from_u8(b)));
     let (i, result) = match le_type {
         LEEventType::LEAdvertisingReport => {
-            try_parse!(i, map!(le_advertising_info, |x| Message::LEAdvertisingReport(x)))
+            try_parse!(i, map!(le_advertising_reports, Message::LEAdvertisingReport))
         }
         LEEventType::LEConnComplete => {
-            try_parse!(i, map!(le_conn_complete, |x| Message::LEConnComplete(x)))
+            try_parse!(i, map!(le_conn_complete, Message::LEConnComplete))
+        }
+        LEEventType::LEEnhancedConnComplete => {
+            try_parse!(i, map!(le_enhanced_conn_complete, Message::LEConnComplete))
         }
         LEEventType::LEReadRemoteUsedFeaturesComplete => {
             try_parse!(i, le_read_remote_used_features_complete)
@@ -626,16 +1107,75 @@ fn le_meta_event(i: &[u8]) -> IResult<&[u8], Message> {
         LEEventType::LEConnUpdateComplete => {
             try_parse!(i, le_conn_update_complete)
         }
+        LEEventType::LEDataLengthChange => {
+            try_parse!(i, le_data_length_change)
+        }
+        LEEventType::LEPhyUpdateComplete => {
+            try_parse!(i, le_phy_update_complete)
+        }
     };
     Ok((i, result))
 }
 
-fn cmd_complete(i: &[u8]) -> IResult<&[u8], Message> {
+named!(cmd_complete<&[u8], Message>,
+    do_parse!(
+      credits: le_u8 >>
+      opcode: le_u16 >>
+      status: le_u8 >>
+      parameters: rest >>
+      (
+          Message::HCICommandComplete {
+              credits, opcode, status,
+              parameters: parameters.to_owned(),
+          }
+      )
+    )
+);
+
+named!(cmd_status<&[u8], Message>,
+    do_parse!(
+      status: le_u8 >>
+      credits: le_u8 >>
+      opcode: le_u16 >>
+      (
+          Message::CommandStatus { credits, opcode, status }
+      )
+    )
+);
+
+// the event mask the kernel sets up for an LE capable controller, which includes BR/EDR
+// Connection Complete and Connection Request, Disconnection Complete, Encryption Change, Read
+// Remote Version Information Complete, Hardware Error, Data Buffer Overflow, Encryption Key
+// Refresh Complete and LE Meta
+const DEFAULT_EVENT_MASK: u64 = 0x2000_8000_fffb_ffff;
+
+// the LE event mask the kernel sets up: LE Connection Complete, Advertising Report, Connection
+// Update Complete, Read Remote Features Complete, Long Term Key Request and Enhanced Connection
+// Complete
+const DEFAULT_LE_EVENT_MASK: u64 = 0x0000_0000_0000_021f;
+
+/// Returns the event mask and the LE event mask to set on a controller, which are the ones the
+/// operating system uses along with the optional LE Data Length Change and LE PHY Update
+/// Complete events.
+pub fn event_masks(data_length_changes: bool, phy_updates: bool) -> (u64, u64) {
+    let mut le_event_mask = DEFAULT_LE_EVENT_MASK;
+    if data_length_changes {
+        le_event_mask |= 1 << 6; // LE Data Length Change
+    }
+    if phy_updates {
+        le_event_mask |= 1 << 11; // LE PHY Update Complete
+    }
+    (DEFAULT_EVENT_MASK, le_event_mask)
+}
+
+/// Parses the return parameters of a command that has completed, which follow the status.
+pub fn command_complete(opcode: u16, status: u8, i: &[u8]) -> IResult<&[u8], CommandComplete> {
     use self::CommandComplete::*;
 
-    let (i, _skip) = try_parse!(i, le_u8);
-    let (i, cmd) = try_parse!(i, map_opt!(le_u16, |b| CommandType::from_u16(b)));
-    let (i, status) = try_parse!(i, le_u8);
+    let cmd = match CommandType::from_u16(opcode) {
+        Some(cmd) => cmd,
+        None => return Ok((&[], Other { opcode, status, data: i.to_owned() })),
+    };
     let result = match cmd {
         CommandType::Reset => Reset,
         CommandType::ReadLEHostSupported => {
@@ -643,31 +1183,78 @@ fn cmd_complete(i: &[u8]) -> IResult<&[u8], Message> {
             let (_, simul) = try_parse!(i, le_u8);
             ReadLEHostSupported { le, simul }
         },
+        CommandType::ReadLocalVersion => {
+            let (i, hci_version) = try_parse!(i, le_u8);
+            let (i, hci_revision) = try_parse!(i, le_u16);
+            let (i, lmp_version) = try_parse!(i, le_u8);
+            let (i, manufacturer) = try_parse!(i, le_u16);
+            let (_, lmp_sub_version) = try_parse!(i, le_u16);
+            ReadLocalVersion {
+                hci_version, hci_revision, lmp_version, manufacturer, lmp_sub_version
+            }
+        },
+        CommandType::ReadBufferSize => {
+            let (i, acl_packet_length) = try_parse!(i, le_u16);
+            let (i, sco_packet_length) = try_parse!(i, le_u8);
+            let (i, acl_packets) = try_parse!(i, le_u16);
+            let (_, sco_packets) = try_parse!(i, le_u16);
+            ReadBufferSize { acl_packet_length, sco_packet_length, acl_packets, sco_packets }
+        },
         CommandType::ReadBDAddr => {
-            let (i, address_type) = try_parse!(i, map_opt!(le_u8, |b| AddressType::from_u8(b)));
             let (_, address) = try_parse!(i, bd_addr);
-
-            ReadBDAddr { address_type, address }
+            ReadBDAddr { address }
         },
-        CommandType::LESetScanParameters => LESetScanParameters,
-        CommandType::LESetScanEnabled => {
-            LESetScanEnabled { enabled: status == 1 }
+        CommandType::LEReadBufferSize => {
+            let (i, packet_length) = try_parse!(i, le_u16);
+            let (_, packets) = try_parse!(i, le_u8);
+            LEReadBufferSize { status, packet_length, packets }
         },
+        CommandType::LEReadLocalSupportedFeatures => {
+            let (_, features) = try_parse!(i, le_u64);
+            LEReadLocalSupportedFeatures {
+                status,
+                features: LEFeatureFlags::from_bits_truncate(features),
+            }
+        },
+        CommandType::LEReadSupportedStates => {
+            let (_, states) = try_parse!(i, le_u64);
+            LEReadSupportedStates { status, states }
+        },
+        CommandType::LESetScanParameters => LESetScanParameters,
         CommandType::ReadRSSI => {
             let (i, handle) = try_parse!(i, le_u16);
-            let (_, rssi) = try_parse!(i, le_u8);
-            ReadRSSI { handle, rssi }
+            let (_, rssi) = try_parse!(i, le_i8);
+            ReadRSSI { status, handle, rssi }
         },
-        x => {
+        CommandType::LEReadWhiteListSize => {
+            let (_, size) = try_parse!(i, le_u8);
+            LEReadWhiteListSize { status, size }
+        },
+        CommandType::LEReadSuggestedDefaultDataLength => {
+            let (i, tx_octets) = try_parse!(i, le_u16);
+            let (_, tx_time) = try_parse!(i, le_u16);
+            LEReadSuggestedDefaultDataLength { status, tx_octets, tx_time }
+        },
+        CommandType::LEReadMaximumDataLength => {
+            let (_, lengths) = try_parse!(i, data_length);
+            LEReadMaximumDataLength { status, lengths }
+        },
+        CommandType::LEReadPHY => {
+            let (i, handle) = try_parse!(i, le_u16);
+            let (i, tx_phy) = try_parse!(i, le_u8);
+            let (_, rx_phy) = try_parse!(i, le_u8);
+            LEReadPHY { status, handle, tx_phy, rx_phy }
+        },
+        _ => {
             Other {
-                command: x,
+                opcode,
                 status,
-                data: i.clone().to_owned()
+                data: i.to_owned()
             }
         }
     };
 
-    Ok((&i, Message::HCICommandComplete(result)))
+    Ok((&[], result))
 }
 
 named!(disconnect_complete<&[u8], Message>,
@@ -683,6 +1270,22 @@ named!(disconnect_complete<&[u8], Message>,
     )
 );
 
+named!(read_remote_version_complete<&[u8], Message>,
+    do_parse!(
+      status: map_opt!(le_u8, HCIStatus::from_u8) >>
+      handle: le_u16 >>
+      version: le_u8 >>
+      manufacturer: le_u16 >>
+      subversion: le_u16 >>
+      (
+          Message::ReadRemoteVersionComplete {
+              status, handle,
+              version: RemoteVersion { version, manufacturer, subversion },
+          }
+      )
+    )
+);
+
 fn hci_event_pkt(i: &[u8]) -> IResult<&[u8], Message> {
     use self::HCIEventSubType::*;
     let (i, sub_type) = try_parse!(i, map_opt!(le_u8, |b| HCIEventSubType::from_u8(b)));
@@ -690,15 +1293,9 @@ fn hci_event_pkt(i: &[u8]) -> IResult<&[u8], Message> {
     let result = match sub_type {
         LEMetaEvent => try_parse!(data, le_meta_event).1,
         CmdComplete => try_parse!(data, cmd_complete).1,
-        CmdStatus => {
-            let (data, status) = try_parse!(data, map_opt!(le_u8, |b| HCIStatus::from_u8(b)));
-            let (data, _) = try_parse!(data, le_u8);
-            let (_, command) = try_parse!(data, map_opt!(le_u16, |b| CommandType::from_u16(b)));
-            Message::CommandStatus {
-                command, status,
-            }
-        },
+        CmdStatus => try_parse!(data, cmd_status).1,
         DisconnComplete => try_parse!(data, disconnect_complete).1,
+        ReadRemoteVersionComplete => try_parse!(data, read_remote_version_complete).1,
         _ => {
             warn!("Unhandled HCIEventPkt subtype {:?}", sub_type);
             return Err(Err::Error(error_position!(i, ErrorKind::Custom(4))));
@@ -749,9 +1346,11 @@ fn hci_acldata_pkt(i: &[u8]) -> IResult<&[u8], Message> {
             }))
         }
         ACL_CONT => {
-            (&[][..], Message::ACLDataContinuation {
+            let (i, dlen) = try_parse!(i, le_u16);
+            let (i, data) = try_parse!(i, take!(dlen));
+            (i, Message::ACLDataContinuation {
                 handle,
-                data: i.clone().to_owned(),
+                data: data.to_owned(),
             })
         },
         x => {
@@ -787,3 +1386,31 @@ pub fn hci_command(command: u16, data: &[u8]) -> BytesMut {
     buf.put(data);
     buf
 }
+
+/// Returns whether a scan interval and window, in units of 0.625 ms, are ones the controller
+/// accepts: both from 0x0004 to 0x4000, with the window no longer than the interval.
+pub fn valid_scan_window(interval: u16, window: u16) -> bool {
+    (0x0004..=0x4000).contains(&interval) && (0x0004..=interval).contains(&window)
+}
+
+/// Returns whether connection parameters are in the ranges the spec allows: intervals from 0x0006
+/// to 0x0C80 in units of 1.25 ms, a latency of at most 0x01F3 and a supervision timeout from
+/// 0x000A to 0x0C80 in units of 10 ms. The supervision timeout must also be longer than
+/// `(1 + latency) * max_interval * 2`, to cover the connection events the device may skip.
+pub fn valid_connection_parameters(min_interval: u16, max_interval: u16, latency: u16,
+                                   timeout: u16) -> bool {
+    (0x0006..=max_interval).contains(&min_interval) && max_interval <= 0x0C80 &&
+        latency <= 0x01F3 && (0x000A..=0x0C80).contains(&timeout) &&
+        u32::from(timeout) * 4 > (1 + u32::from(latency)) * u32::from(max_interval)
+}
+
+/// Encodes the parameters of an LE Set Scan Parameters command.
+pub fn le_scan_parameters(params: &ScanParameters) -> BytesMut {
+    let mut data = BytesMut::with_capacity(7);
+    data.put_u8(if params.active { 1 } else { 0 }); // scan_type = active or passive
+    data.put_u16_le(params.interval); // interval, 0.625 ms units
+    data.put_u16_le(params.window); // window, 0.625 ms units
+    data.put_u8(params.own_address_type.num()); // own_type
+    data.put_u8(params.filter_policy.num()); // filter_policy
+    data
+}
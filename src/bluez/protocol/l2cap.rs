@@ -0,0 +1,66 @@
+use nom::{le_u8, le_u16, IResult};
+
+use bluez::constants::*;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conn_param_update_request() {
+        let buf = [0x12, 7, 8, 0, 0x18, 0, 0x28, 0, 0, 0, 0x2a, 0];
+        assert_eq!(signaling_command(&buf), Ok((
+            &[][..],
+            SignalingCommand::ConnectionParameterUpdateRequest {
+                identifier: 7,
+                min_interval: 0x18,
+                max_interval: 0x28,
+                latency: 0,
+                timeout: 0x2a,
+            }
+        )));
+    }
+
+    #[test]
+    fn test_other_command() {
+        let buf = [0x01, 3, 2, 0, 0, 0];
+        assert_eq!(signaling_command(&buf), Ok((
+            &[][..],
+            SignalingCommand::Other { code: 0x01, identifier: 3 }
+        )));
+    }
+}
+
+/// A command received on the LE signaling channel.
+#[derive(Debug, PartialEq)]
+pub enum SignalingCommand {
+    ConnectionParameterUpdateRequest {
+        identifier: u8,
+        min_interval: u16,
+        max_interval: u16,
+        latency: u16,
+        timeout: u16,
+    },
+    Other {
+        code: u8,
+        identifier: u8,
+    },
+}
+
+pub fn signaling_command(i: &[u8]) -> IResult<&[u8], SignalingCommand> {
+    let (i, code) = try_parse!(i, le_u8);
+    let (i, identifier) = try_parse!(i, le_u8);
+    let (i, data) = try_parse!(i, length_bytes!(le_u16));
+
+    let command = match code {
+        L2CAP_CONN_PARAM_UPDATE_REQ => {
+            let (_, (min_interval, max_interval, latency, timeout)) =
+                try_parse!(data, tuple!(le_u16, le_u16, le_u16, le_u16));
+            SignalingCommand::ConnectionParameterUpdateRequest {
+                identifier, min_interval, max_interval, latency, timeout
+            }
+        }
+        code => SignalingCommand::Other { code, identifier },
+    };
+    Ok((i, command))
+}
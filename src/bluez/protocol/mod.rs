@@ -1,5 +1,6 @@
 pub mod hci;
 pub mod att;
+pub mod l2cap;
 
 use nom::le_u8;
 
@@ -90,8 +90,12 @@ extern crate failure;
 #[macro_use]
 extern crate failure_derive;
 
+#[cfg(feature = "async")]
+extern crate futures_core;
+
 use std::result;
 use std::time::Duration;
+use bluez::HCIStatus;
 
 pub mod bluez;
 pub mod api;
@@ -113,9 +117,109 @@ pub enum Error {
     #[fail(display = "Timed out after {:?}", _0)]
     TimedOut(Duration),
 
+    #[fail(display = "The operation was cancelled")]
+    Cancelled,
+
+    #[fail(display = "The adapter failed to carry out the command: {:?}", _0)]
+    Hci(HCIStatus),
+
+    #[fail(display = "ATT request 0x{:02X} on handle 0x{:04X} failed: {:?}",
+           request_opcode, handle, code)]
+    Att {
+        request_opcode: u8,
+        handle: u16,
+        code: AttError,
+    },
+
     #[fail(display = "{}", _0)]
     Other(String),
 }
 
+/// The error codes a device may send in response to an ATT request that it could not complete.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AttError {
+    InvalidHandle,
+    ReadNotPermitted,
+    WriteNotPermitted,
+    InvalidPdu,
+    InsufficientAuthentication,
+    RequestNotSupported,
+    InvalidOffset,
+    InsufficientAuthorization,
+    PrepareQueueFull,
+    AttributeNotFound,
+    AttributeNotLong,
+    InsufficientEncryptionKeySize,
+    InvalidAttributeValueLength,
+    UnlikelyError,
+    InsufficientEncryption,
+    UnsupportedGroupType,
+    InsufficientResources,
+    DatabaseOutOfSync,
+    ValueNotAllowed,
+    /// An error defined by the application running on the device (0x80-0x9F).
+    Application(u8),
+    /// An error defined by a common profile or service specification (0xE0-0xFF).
+    CommonProfile(u8),
+    /// An error code reserved for future use.
+    Reserved(u8),
+}
+
+impl AttError {
+    pub fn from_u8(v: u8) -> AttError {
+        use AttError::*;
+        match v {
+            0x01 => InvalidHandle,
+            0x02 => ReadNotPermitted,
+            0x03 => WriteNotPermitted,
+            0x04 => InvalidPdu,
+            0x05 => InsufficientAuthentication,
+            0x06 => RequestNotSupported,
+            0x07 => InvalidOffset,
+            0x08 => InsufficientAuthorization,
+            0x09 => PrepareQueueFull,
+            0x0A => AttributeNotFound,
+            0x0B => AttributeNotLong,
+            0x0C => InsufficientEncryptionKeySize,
+            0x0D => InvalidAttributeValueLength,
+            0x0E => UnlikelyError,
+            0x0F => InsufficientEncryption,
+            0x10 => UnsupportedGroupType,
+            0x11 => InsufficientResources,
+            0x12 => DatabaseOutOfSync,
+            0x13 => ValueNotAllowed,
+            0x80..=0x9F => Application(v),
+            0xE0..=0xFF => CommonProfile(v),
+            _ => Reserved(v),
+        }
+    }
+
+    pub fn num(&self) -> u8 {
+        use AttError::*;
+        match *self {
+            InvalidHandle => 0x01,
+            ReadNotPermitted => 0x02,
+            WriteNotPermitted => 0x03,
+            InvalidPdu => 0x04,
+            InsufficientAuthentication => 0x05,
+            RequestNotSupported => 0x06,
+            InvalidOffset => 0x07,
+            InsufficientAuthorization => 0x08,
+            PrepareQueueFull => 0x09,
+            AttributeNotFound => 0x0A,
+            AttributeNotLong => 0x0B,
+            InsufficientEncryptionKeySize => 0x0C,
+            InvalidAttributeValueLength => 0x0D,
+            UnlikelyError => 0x0E,
+            InsufficientEncryption => 0x0F,
+            UnsupportedGroupType => 0x10,
+            InsufficientResources => 0x11,
+            DatabaseOutOfSync => 0x12,
+            ValueNotAllowed => 0x13,
+            Application(v) | CommonProfile(v) | Reserved(v) => v,
+        }
+    }
+}
+
 // Rumble Result type
 pub type Result<T> = result::Result<T, Error>;